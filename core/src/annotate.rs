@@ -0,0 +1,190 @@
+// Pretty-printer annotations for code that came from a macro expansion, in the
+// backends over a `syntax` crate: markers around it for the HTML and JSON
+// traces, and provenance comments before it.
+//
+// As with `span_fixing!`, this is a macro expanded in each backend, where
+// `syntax` names the backend's crate. `annotate!()` defines the `annotate`
+// module, with `MarkingAnn`, `ProvenanceAnn` and `origin`.
+#[macro_export]
+macro_rules! annotate {
+    () => {
+        mod annotate {
+            use syntax::codemap::{CodeMap, ExpnId, Span, NO_EXPANSION};
+            use syntax::print::pp;
+            use syntax::print::pprust::{AnnNode, PpAnn, State};
+
+            use $crate::events::Event;
+            use $crate::html::Origin;
+            use $crate::markers::{self, Side, CLOSE};
+
+            use std::cell::RefCell;
+            use std::collections::HashMap;
+            use std::io;
+
+            // Marks every node for which `mark_of` gives an id, with the side of the
+            // invocation it was written on. Nodes inside a marked node with the same id
+            // and side are not marked again.
+            pub struct MarkingAnn<F> {
+                mark_of: F,
+                // The mark of every node being printed, innermost last, or `None` for
+                // nodes that did not get a marker.
+                open: RefCell<Vec<Option<(usize, Side)>>>,
+            }
+
+            impl<F: Fn(Span) -> Option<(usize, Side)>> MarkingAnn<F> {
+                pub fn new(mark_of: F) -> MarkingAnn<F> {
+                    MarkingAnn { mark_of: mark_of, open: RefCell::new(Vec::new()) }
+                }
+            }
+
+            fn node_span(node: &AnnNode) -> Option<Span> {
+                match *node {
+                    AnnNode::NodeBlock(block) => Some(block.span),
+                    AnnNode::NodeItem(item) => Some(item.span),
+                    AnnNode::NodeExpr(expr) => Some(expr.span),
+                    AnnNode::NodePat(pat) => Some(pat.span),
+                    _ => None,
+                }
+            }
+
+            impl<F: Fn(Span) -> Option<(usize, Side)>> PpAnn for MarkingAnn<F> {
+                fn pre(&self, state: &mut State, node: AnnNode) -> io::Result<()> {
+                    let mark = node_span(&node).and_then(|span| (self.mark_of)(span));
+                    let mut open = self.open.borrow_mut();
+                    let enclosing = open.iter().rev().filter_map(|mark| *mark).next();
+                    match mark {
+                        Some((id, side)) if enclosing != mark => {
+                            open.push(mark);
+                            pp::zero_word(&mut state.s, &markers::open(id, side))
+                        }
+                        _ => {
+                            open.push(None);
+                            Ok(())
+                        }
+                    }
+                }
+
+                fn post(&self, state: &mut State, node: AnnNode) -> io::Result<()> {
+                    // The printer only calls `post` for identifiers and names.
+                    match node {
+                        AnnNode::NodeIdent(_) | AnnNode::NodeName(_) => return Ok(()),
+                        _ => {}
+                    }
+                    match self.open.borrow_mut().pop() {
+                        Some(Some(_)) => pp::zero_word(&mut state.s, &CLOSE.to_string()),
+                        _ => Ok(()),
+                    }
+                }
+            }
+
+            // Writes a comment naming the invocation that produced each item, block and
+            // expression that came from a macro, before it. Items get a line comment, the
+            // rest a block comment, as they may be in the middle of a line.
+            pub struct ProvenanceAnn<'a> {
+                events: &'a [Event],
+                event_ids: &'a HashMap<ExpnId, usize>,
+                // As in `MarkingAnn`, so that code from one expansion gets one comment.
+                open: RefCell<Vec<Option<usize>>>,
+            }
+
+            impl<'a> ProvenanceAnn<'a> {
+                pub fn new(events: &'a [Event], event_ids: &'a HashMap<ExpnId, usize>) -> ProvenanceAnn<'a> {
+                    ProvenanceAnn { events: events, event_ids: event_ids, open: RefCell::new(Vec::new()) }
+                }
+
+                fn comment(&self, id: usize) -> String {
+                    let event = &self.events[id];
+                    format!("expanded from {} at {}:{}:{} (step {})",
+                            event.name,
+                            event.call_site.file,
+                            event.call_site.lo.line,
+                            event.call_site.lo.col,
+                            event.step)
+                }
+            }
+
+            impl<'a> PpAnn for ProvenanceAnn<'a> {
+                fn pre(&self, state: &mut State, node: AnnNode) -> io::Result<()> {
+                    let span = match node {
+                        AnnNode::NodeItem(item) => Some(item.span),
+                        AnnNode::NodeBlock(block) => Some(block.span),
+                        AnnNode::NodeExpr(expr) => Some(expr.span),
+                        _ => None,
+                    };
+                    let id = span.and_then(|span| self.event_ids.get(&span.expn_id).cloned());
+                    let mut open = self.open.borrow_mut();
+                    let enclosing = open.iter().rev().filter_map(|id| *id).next();
+                    let id = match id {
+                        Some(id) if enclosing != Some(id) => id,
+                        _ => {
+                            open.push(None);
+                            return Ok(());
+                        }
+                    };
+                    open.push(Some(id));
+                    match node {
+                        AnnNode::NodeItem(_) => {
+                            try!(pp::word(&mut state.s, &format!("// {}", self.comment(id))));
+                            pp::hardbreak(&mut state.s)
+                        }
+                        _ => {
+                            try!(pp::word(&mut state.s, &format!("/* {} */", self.comment(id))));
+                            pp::space(&mut state.s)
+                        }
+                    }
+                }
+
+                fn post(&self, _state: &mut State, node: AnnNode) -> io::Result<()> {
+                    match node {
+                        AnnNode::NodeIdent(_) | AnnNode::NodeName(_) => {}
+                        _ => {
+                            self.open.borrow_mut().pop();
+                        }
+                    }
+                    Ok(())
+                }
+            }
+
+            // Follow the call sites of `span` back to the invocation in `filename`, whose
+            // contents are `source`.
+            pub fn origin(codemap: &CodeMap, filename: &str, source: &str, span: Span) -> Option<Origin> {
+                let mut chain = Vec::new();
+                let mut call_site = span;
+                while call_site.expn_id != NO_EXPANSION {
+                    let info = codemap.with_expn_info(call_site.expn_id, |ei| {
+                        ei.map(|ei| (ei.callee.name(), ei.call_site.clone()))
+                    });
+                    let (name, next) = match info {
+                        Some(info) => info,
+                        None => break,
+                    };
+                    chain.insert(0, format!("{}!", name));
+                    if next.expn_id == call_site.expn_id {
+                        break;
+                    }
+                    call_site = next;
+                }
+                if chain.is_empty() {
+                    return None;
+                }
+
+                let lo = codemap.lookup_byte_offset(call_site.lo);
+                let hi = codemap.lookup_byte_offset(call_site.hi);
+                if lo.fm.name != filename {
+                    return None;
+                }
+                let (lo, hi) = (lo.pos.0 as usize, hi.pos.0 as usize);
+                if lo > hi || hi > source.len() {
+                    return None;
+                }
+                let start = codemap.lookup_char_pos(call_site.lo);
+                Some(Origin {
+                    chain: chain,
+                    location: format!("{}:{}", start.line, start.col.0 + 1),
+                    lo: source[..lo].chars().count(),
+                    hi: source[..hi].chars().count(),
+                })
+            }
+        }
+    }
+}
@@ -0,0 +1,66 @@
+// Undoing a run of the expander that gave up midway, in the backends over a
+// `syntax` crate. The expander gives up by unwinding out of the crate, so what
+// it pushed onto the expansion context is left there: its backtrace, module
+// path and recursion count, the macros it exported, and the frames of the macro
+// environment it had entered, with every macro registered in them.
+//
+// As with `span_fixing!`, this is a macro expanded in each backend, where
+// `syntax` names the backend's crate. `attempt!()` defines the `attempt`
+// module, with `Snapshot`.
+#[macro_export]
+macro_rules! attempt {
+    () => {
+        mod attempt {
+            use syntax::ast;
+            use syntax::codemap::ExpnId;
+            use syntax::ext::base::{ExtCtxt, SyntaxExtension};
+            use syntax::parse::token;
+
+            // The state of an expansion context before a run of the expander.
+            //
+            // The macro environment cannot be copied, so the snapshot pushes a frame
+            // of its own onto it instead, holding a name no code can write. Every
+            // frame the run pushes goes above it, and so does every macro the run
+            // registers, so popping frames until the name is gone puts the
+            // environment back as it was. After a run that succeeds the frame is
+            // left, as the macros it holds belong to the step.
+            //
+            // The expansions the run recorded stay in the codemap, which has no way
+            // to remove them; no span of the current step refers to them, so no
+            // event is recorded for them.
+            pub struct Snapshot {
+                backtrace: ExpnId,
+                mod_path: Vec<ast::Ident>,
+                recursion_count: usize,
+                exported_macros: usize,
+                barrier: ast::Name,
+            }
+
+            impl Snapshot {
+                pub fn take(cx: &mut ExtCtxt) -> Snapshot {
+                    let barrier = token::gensym("<attempt>");
+                    cx.syntax_env.push_frame();
+                    cx.syntax_env.insert(barrier, SyntaxExtension::MacroRulesTT);
+                    Snapshot {
+                        backtrace: cx.backtrace,
+                        mod_path: cx.mod_path.clone(),
+                        recursion_count: cx.recursion_count,
+                        exported_macros: cx.exported_macros.len(),
+                        barrier: barrier,
+                    }
+                }
+
+                // Put `cx` back as it was when the snapshot was taken.
+                pub fn restore(self, cx: &mut ExtCtxt) {
+                    while cx.syntax_env.find(self.barrier).is_some() {
+                        cx.syntax_env.pop_frame();
+                    }
+                    cx.backtrace = self.backtrace;
+                    cx.mod_path = self.mod_path;
+                    cx.recursion_count = self.recursion_count;
+                    cx.exported_macros.truncate(self.exported_macros);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,51 @@
+// Expansion backtraces, used to explain why the expansion loop was stopped.
+
+// One macro invocation in a backtrace.
+#[derive(Clone, Debug)]
+pub struct Frame {
+    // The macro name, with its `!`.
+    pub name: String,
+    // Where the macro was invoked, as `file:line:col: line:col`.
+    pub call_site: String,
+}
+
+// Number of frames shown when reporting a backtrace.
+const SHOWN_FRAMES: usize = 8;
+
+// The shortest sequence of macro names that repeats through the whole of
+// `frames` (innermost first), returned in call order. Returns `None` when no
+// name repeats.
+pub fn repeating_chain(frames: &[Frame]) -> Option<Vec<String>> {
+    let len = frames.len();
+    for period in 1..len / 2 + 1 {
+        if (0..len - period).all(|i| frames[i].name == frames[i + period].name) {
+            let mut chain: Vec<String> = frames[..period].iter()
+                                                         .rev()
+                                                         .map(|frame| frame.name.clone())
+                                                         .collect();
+            chain.push(chain[0].clone());
+            return Some(chain);
+        }
+    }
+    None
+}
+
+// Describe a limit that stopped the expansion, using the backtrace of the
+// most deeply nested pending invocation.
+pub fn limit_error(limit: &str, frames: &[Frame], last_step: &str) -> String {
+    let mut msg = limit.to_owned();
+    if let Some(chain) = repeating_chain(frames) {
+        msg.push_str(&format!("\n  repeating invocation chain: {}", chain.join(" -> ")));
+    }
+    if !frames.is_empty() {
+        msg.push_str(&format!("\n  most deeply nested invocation ({} level(s)):", frames.len()));
+        for frame in frames.iter().take(SHOWN_FRAMES) {
+            msg.push_str(&format!("\n    {} at {}", frame.name, frame.call_site));
+        }
+        if frames.len() > SHOWN_FRAMES {
+            msg.push_str(&format!("\n    ... {} more", frames.len() - SHOWN_FRAMES));
+        }
+    }
+    msg.push_str(&format!("\n  last good step: {}", last_step));
+    msg
+}
@@ -0,0 +1,517 @@
+use getopts::{Matches, Options as Opts};
+
+use output;
+use session::{SessionOptions, DEFAULT_MAX_DEPTH};
+
+use std::path::{Path, PathBuf};
+
+// What to do with the expansion steps once they have been produced.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Command {
+    // Write one output file per step (the original behaviour).
+    Expand,
+    // Print every step to stdout.
+    Trace,
+    // Print the changes between consecutive steps to stdout.
+    Diff,
+    // Print a summary of the expansion.
+    Stats,
+    // Remove the step files written by an earlier run.
+    Clean,
+    // Print the expansions that the code at a position in a step came through.
+    Whence,
+    // Print only the code an invocation in the input file expands to.
+    Outcome,
+}
+
+impl Command {
+    fn from_str(s: &str) -> Option<Command> {
+        match s {
+            "expand" => Some(Command::Expand),
+            "trace" => Some(Command::Trace),
+            "diff" => Some(Command::Diff),
+            "stats" => Some(Command::Stats),
+            "clean" => Some(Command::Clean),
+            "whence" => Some(Command::Whence),
+            "outcome" => Some(Command::Outcome),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    Text,
+    // A single standalone page for viewing every step.
+    Html,
+    // Every step and every expansion event, for other tools.
+    Json,
+    // The tree of invocations, as a Graphviz graph.
+    Dot,
+}
+
+impl OutputFormat {
+    fn from_str(s: &str) -> Option<OutputFormat> {
+        match s {
+            "text" => Some(OutputFormat::Text),
+            "html" => Some(OutputFormat::Html),
+            "json" => Some(OutputFormat::Json),
+            "dot" => Some(OutputFormat::Dot),
+            _ => None,
+        }
+    }
+}
+
+// Which pending invocation to expand first when expanding one per step.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Order {
+    // The least deeply nested invocation, so every step stays close to the source.
+    Outermost,
+    // The most deeply nested invocation, so each expansion is followed to the end.
+    Innermost,
+}
+
+impl Order {
+    fn from_str(s: &str) -> Option<Order> {
+        match s {
+            "outer" => Some(Order::Outermost),
+            "inner" => Some(Order::Innermost),
+            _ => None,
+        }
+    }
+}
+
+// How printed steps show hygiene, which the printer otherwise hides: identifiers
+// a macro introduced print the same as the user's.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Hygiene {
+    // Every identifier with a syntax context is followed by it, as in `x#3`.
+    Marks,
+    // The local variables macros introduced are renamed apart, as in `x_3`, so
+    // that the step keeps its meaning when compiled.
+    Rename,
+}
+
+impl Hygiene {
+    fn from_str(s: &str) -> Option<Hygiene> {
+        match s {
+            "marks" => Some(Hygiene::Marks),
+            "rename" => Some(Hygiene::Rename),
+            _ => None,
+        }
+    }
+}
+
+// Which macros are expanded, from `--only` and `--skip`. Invocations of any
+// other macro are left as they are in every step.
+#[derive(Clone, Debug, Default)]
+pub struct MacroFilter {
+    only: Vec<String>,
+    skip: Vec<String>,
+}
+
+impl MacroFilter {
+    // Expand only the macros in `only`, when it is not empty, and none of those in `skip`.
+    pub fn new(only: Vec<String>, skip: Vec<String>) -> MacroFilter {
+        MacroFilter { only: only, skip: skip }
+    }
+
+    // Whether invocations of the macro at `path` (without its `!`) are expanded.
+    // Names match either the whole path or its last segment.
+    pub fn allows(&self, path: &str) -> bool {
+        let last = path.rsplit("::").next().unwrap_or(path);
+        let listed = |names: &Vec<String>| names.iter().any(|name| name == path || name == last);
+        (self.only.is_empty() || listed(&self.only)) && !listed(&self.skip)
+    }
+}
+
+pub struct Options {
+    pub command: Command,
+    pub filename: String,
+    pub out_dir: Option<PathBuf>,
+    pub name_template: String,
+    pub force: bool,
+    pub max_steps: Option<usize>,
+    pub max_depth: usize,
+    // Expand a single invocation per step, rather than every pending one.
+    pub one_by_one: bool,
+    pub order: Order,
+    pub filter: MacroFilter,
+    // Compare tokens rather than lines in `diff`.
+    pub diff_tokens: bool,
+    // Comment expanded code with the invocation it came from.
+    pub provenance: bool,
+    pub hygiene: Option<Hygiene>,
+    // Report how each expanded invocation matched its definition.
+    pub rules: bool,
+    // Colour the code that came from a macro in `trace` by the side of its
+    // invocation it was written on.
+    pub color: bool,
+    // For `whence` and `outcome`, the 1-based line and column asked about, and
+    // the step to look in, the last when not given.
+    pub position: Option<(usize, usize)>,
+    // For `whence`, the module file the position is in: the crate root
+    // `filename`, unless `--root` names another.
+    pub module_file: Option<String>,
+    pub step: Option<usize>,
+    // For `outcome`, the invocation asked about by its index in the input
+    // file, instead of a position.
+    pub invocation: Option<usize>,
+    pub format: OutputFormat,
+}
+
+impl Options {
+    // Where step files are written: `--out-dir`, or next to the input file.
+    pub fn output_dir(&self) -> PathBuf {
+        match self.out_dir {
+            Some(ref dir) => dir.clone(),
+            None => Path::new(&self.filename).parent().unwrap_or(Path::new("")).to_path_buf(),
+        }
+    }
+
+    // How the crate is stepped through, for the backend's session.
+    pub fn session_options(&self) -> SessionOptions {
+        SessionOptions {
+            max_steps: self.max_steps,
+            max_depth: self.max_depth,
+            one_by_one: self.one_by_one,
+            order: self.order,
+            filter: self.filter.clone(),
+            provenance: self.provenance,
+            hygiene: self.hygiene,
+        }
+    }
+}
+
+pub enum Action {
+    Help,
+    Run(Options),
+}
+
+fn opts() -> Opts {
+    let mut opts = Opts::new();
+    opts.optflag("h", "help", "print this help message");
+    opts.optopt("o", "out-dir", "directory to write step files to \
+                                 (default: next to the input file)", "DIR");
+    opts.optopt("", "name-template", "file name for each step; {stem} is replaced by the \
+                                       input file's stem and {step} by the step number \
+                                       (default: {stem}Output{step}.rs)", "TEMPLATE");
+    opts.optflag("", "force", "overwrite existing files that were not written by an \
+                               earlier run");
+    opts.optopt("", "max-steps", "stop after at most N expansion steps", "N");
+    opts.optopt("", "max-depth", "stop once an invocation is nested N expansions deep \
+                                   (default: 64)", "N");
+    opts.optflag("1", "one-by-one", "expand a single invocation per step");
+    opts.optopt("", "order", "which invocation --one-by-one expands first: outer or inner \
+                               (default: outer)", "ORDER");
+    opts.optmulti("", "only", "expand only the listed macros, leaving every other \
+                               invocation unexpanded", "NAME,...");
+    opts.optmulti("", "skip", "leave the listed macros unexpanded", "NAME,...");
+    opts.optflag("", "tokens", "with diff, compare tokens rather than lines, ignoring how \
+                                the pretty printer breaks lines");
+    opts.optflag("p", "provenance", "put a comment naming the invocation it came from \
+                                     before expanded code");
+    opts.optopt("", "hygiene", "show hygiene in printed steps: marks (x#3) or rename \
+                                 (x_3)", "MODE");
+    opts.optflag("", "rules", "report the macro_rules arm each expanded invocation matched, \
+                               its metavariable bindings and the arms that failed before it");
+    opts.optflag("", "color", "with trace, colour code from a macro's body and code from an \
+                               invocation's arguments differently");
+    opts.optopt("", "step", "with whence or outcome, the step to look in (default: the last)", "N");
+    opts.optopt("", "root", "with whence, the root file of the crate when FILE is another \
+                             of its module files", "ROOT");
+    opts.optopt("", "invocation", "with outcome, pick the Nth invocation expanded in FILE, \
+                                   counting from 0, instead of one at FILE:LINE:COL", "N");
+    opts.optopt("f", "format", "output format: text, html, json or dot (all but text with \
+                                expand and trace only, and json with whence and outcome)", "FORMAT");
+    opts
+}
+
+pub fn usage() -> String {
+    let brief = format!("Usage: {} <command> [options] FILE\n\n\
+                         Commands:\n    \
+                         expand    write one file per expansion step (default)\n    \
+                         trace     print every expansion step to stdout\n    \
+                         diff      print a unified diff of each expansion step\n    \
+                         stats     print a summary of the expansion\n    \
+                         clean     remove the files written by an earlier run\n    \
+                         whence    print where the code at FILE:LINE:COL of a step file came from\n    \
+                         outcome   print only the code the invocation at FILE:LINE:COL expands to",
+                        env!("CARGO_PKG_NAME"));
+    opts().usage(&brief)
+}
+
+pub fn short_usage() -> String {
+    format!("Usage: {} <command> [options] FILE\n\
+             Try '{} --help' for more information.",
+            env!("CARGO_PKG_NAME"), env!("CARGO_PKG_NAME"))
+}
+
+// Parse the arguments following the program name.
+pub fn parse_args(args: &[String]) -> Result<Action, String> {
+    let matches = try!(opts().parse(args).map_err(|e| e.to_string()));
+    if matches.opt_present("h") {
+        return Ok(Action::Help);
+    }
+
+    let mut free = matches.free.iter();
+    let (command, filename) = match (free.next(), free.next()) {
+        (None, _) => return Err("no input file given".to_owned()),
+        (Some(arg), None) => {
+            if Command::from_str(arg).is_some() {
+                return Err("no input file given".to_owned());
+            }
+            (Command::Expand, arg.clone())
+        }
+        (Some(cmd), Some(file)) => {
+            let command = try!(Command::from_str(cmd)
+                               .ok_or(format!("unknown command '{}'", cmd)));
+            (command, file.clone())
+        }
+    };
+    if let Some(extra) = free.next() {
+        return Err(format!("unexpected argument '{}'; \
+                            please supply a single filepath", extra));
+    }
+
+    let applies = [
+        ("color", command == Command::Trace, "trace"),
+        ("tokens", command == Command::Diff, "diff"),
+        ("invocation", command == Command::Outcome, "outcome"),
+        ("root", command == Command::Whence, "whence"),
+        ("step", command == Command::Whence || command == Command::Outcome, "whence or outcome"),
+    ];
+    for &(flag, applies, commands) in &applies {
+        if matches.opt_present(flag) && !applies {
+            return Err(format!("--{} can only be used with {}", flag, commands));
+        }
+    }
+
+    let invocation = try!(parse_number(&matches, "invocation"));
+    let by_position = match command {
+        Command::Whence => true,
+        Command::Outcome => invocation.is_none(),
+        _ => false,
+    };
+    let (filename, position) = if by_position {
+        let (filename, line, col) = try!(parse_position(command, &filename));
+        (filename, Some((line, col)))
+    } else {
+        (filename, None)
+    };
+    let (filename, module_file) = if command == Command::Whence {
+        (matches.opt_str("root").unwrap_or(filename.clone()), Some(filename))
+    } else {
+        (filename, None)
+    };
+
+    let format = try!(parse_format(&matches));
+    let formats = match command {
+        Command::Expand | Command::Trace => true,
+        Command::Whence | Command::Outcome => format == OutputFormat::Json,
+        _ => false,
+    };
+    if format != OutputFormat::Text && !formats {
+        return Err(format!("--format {} can only be used with expand or trace, \
+                            or json with whence and outcome",
+                           matches.opt_str("format").unwrap()));
+    }
+
+    let name_template = matches.opt_str("name-template")
+                               .unwrap_or(output::DEFAULT_TEMPLATE.to_owned());
+    try!(output::check_template(&name_template));
+
+    Ok(Action::Run(Options {
+        command: command,
+        filename: filename,
+        out_dir: matches.opt_str("out-dir").map(PathBuf::from),
+        name_template: name_template,
+        force: matches.opt_present("force"),
+        max_steps: try!(parse_number(&matches, "max-steps")),
+        max_depth: try!(parse_number(&matches, "max-depth")).unwrap_or(DEFAULT_MAX_DEPTH),
+        one_by_one: matches.opt_present("one-by-one"),
+        order: try!(parse_order(&matches)),
+        filter: MacroFilter {
+            only: parse_names(&matches, "only"),
+            skip: parse_names(&matches, "skip"),
+        },
+        diff_tokens: matches.opt_present("tokens"),
+        provenance: matches.opt_present("provenance"),
+        hygiene: try!(parse_hygiene(&matches)),
+        rules: matches.opt_present("rules"),
+        color: matches.opt_present("color"),
+        position: position,
+        module_file: module_file,
+        step: try!(parse_number(&matches, "step")),
+        invocation: invocation,
+        format: format,
+    }))
+}
+
+fn parse_number(matches: &Matches, name: &str) -> Result<Option<usize>, String> {
+    match matches.opt_str(name) {
+        Some(s) => s.parse().map(Some)
+                    .map_err(|_| format!("--{} expects a number, found '{}'", name, s)),
+        None => Ok(None),
+    }
+}
+
+// The file, line and column in the `FILE:LINE:COL` of `whence` or `outcome`.
+fn parse_position(command: Command, arg: &str) -> Result<(String, usize, usize), String> {
+    let mut parts = arg.rsplitn(3, ':');
+    let col = parts.next().and_then(|col| col.parse().ok());
+    let line = parts.next().and_then(|line| line.parse().ok());
+    match (parts.next(), line, col) {
+        (Some(file), Some(line), Some(col)) if !file.is_empty() => Ok((file.to_owned(), line, col)),
+        _ => {
+            let name = if command == Command::Whence { "whence" } else { "outcome" };
+            Err(format!("{} expects FILE:LINE:COL, found '{}'", name, arg))
+        }
+    }
+}
+
+fn parse_format(matches: &Matches) -> Result<OutputFormat, String> {
+    match matches.opt_str("format") {
+        Some(s) => OutputFormat::from_str(&s)
+                   .ok_or(format!("unknown output format '{}'", s)),
+        None => Ok(OutputFormat::Text),
+    }
+}
+
+fn parse_order(matches: &Matches) -> Result<Order, String> {
+    match matches.opt_str("order") {
+        Some(s) => Order::from_str(&s).ok_or(format!("unknown order '{}'", s)),
+        None => Ok(Order::Outermost),
+    }
+}
+
+fn parse_hygiene(matches: &Matches) -> Result<Option<Hygiene>, String> {
+    match matches.opt_str("hygiene") {
+        Some(s) => Hygiene::from_str(&s).map(Some).ok_or(format!("unknown hygiene mode '{}'", s)),
+        None => Ok(None),
+    }
+}
+
+// Macro names given to `name`, which may be repeated and comma separated. A
+// trailing `!` is allowed.
+fn parse_names(matches: &Matches, name: &str) -> Vec<String> {
+    matches.opt_strs(name)
+           .iter()
+           .flat_map(|list| list.split(','))
+           .map(|name| name.trim().trim_right_matches('!').to_owned())
+           .filter(|name| !name.is_empty())
+           .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_args, Action, Command, Hygiene, Options, Order, OutputFormat};
+
+    fn parse(args: &str) -> Result<Options, String> {
+        let args: Vec<String> = args.split_whitespace().map(|arg| arg.to_owned()).collect();
+        parse_args(&args).map(|action| match action {
+            Action::Run(options) => options,
+            Action::Help => panic!("`{:?}` asks for help", args),
+        })
+    }
+
+    fn error(args: &str) -> String {
+        parse(args).err().unwrap_or_else(|| panic!("`{}` parses", args))
+    }
+
+    #[test]
+    fn a_lone_file_is_expanded() {
+        let options = parse("foo.rs").unwrap();
+        assert_eq!(options.command, Command::Expand);
+        assert_eq!(options.filename, "foo.rs");
+        assert_eq!(options.format, OutputFormat::Text);
+        assert_eq!(options.order, Order::Outermost);
+    }
+
+    #[test]
+    fn every_command_is_parsed() {
+        let commands = [("expand", Command::Expand), ("trace", Command::Trace),
+                        ("diff", Command::Diff), ("stats", Command::Stats),
+                        ("clean", Command::Clean)];
+        for &(name, command) in &commands {
+            let options = parse(&format!("{} foo.rs", name)).unwrap();
+            assert_eq!(options.command, command);
+            assert_eq!(options.filename, "foo.rs");
+        }
+        let options = parse("whence foo.rs:3:5").unwrap();
+        assert_eq!((options.command, &options.filename[..]), (Command::Whence, "foo.rs"));
+        assert_eq!(options.position, Some((3, 5)));
+        assert_eq!(options.module_file, Some("foo.rs".to_owned()));
+        let options = parse("whence --root lib.rs foo/bar.rs:3:5").unwrap();
+        assert_eq!(options.filename, "lib.rs");
+        assert_eq!(options.module_file, Some("foo/bar.rs".to_owned()));
+        let options = parse("outcome --invocation 2 foo.rs").unwrap();
+        assert_eq!((options.command, options.invocation, options.position),
+                   (Command::Outcome, Some(2), None));
+    }
+
+    #[test]
+    fn help_is_asked_for() {
+        let args = vec!["trace".to_owned(), "--help".to_owned()];
+        assert!(match parse_args(&args) { Ok(Action::Help) => true, _ => false });
+    }
+
+    #[test]
+    fn options_are_parsed() {
+        let options = parse("trace -o out --name-template {step}.rs --force --max-steps 3 \
+                             --max-depth 9 -1 --order inner --only a,b! --only c --skip d \
+                             -p --hygiene rename --rules --color -f html foo.rs").unwrap();
+        assert_eq!(options.output_dir().to_str(), Some("out"));
+        assert_eq!(options.name_template, "{step}.rs");
+        assert!(options.force && options.one_by_one && options.provenance);
+        assert!(options.rules && options.color);
+        assert_eq!((options.max_steps, options.max_depth), (Some(3), 9));
+        assert_eq!(options.order, Order::Innermost);
+        assert!(options.filter.allows("a") && options.filter.allows("b"));
+        assert!(options.filter.allows("c") && !options.filter.allows("e"));
+        assert_eq!(options.hygiene, Some(Hygiene::Rename));
+        assert_eq!(options.format, OutputFormat::Html);
+        assert!(parse("diff --tokens foo.rs").unwrap().diff_tokens);
+        assert_eq!(parse("whence --step 2 -f json foo.rs:1:1").unwrap().step, Some(2));
+    }
+
+    #[test]
+    fn flags_are_refused_with_commands_they_do_not_apply_to() {
+        assert_eq!(error("expand --color foo.rs"), "--color can only be used with trace");
+        assert_eq!(error("trace --tokens foo.rs"), "--tokens can only be used with diff");
+        assert_eq!(error("whence --invocation 1 foo.rs:1:1"),
+                   "--invocation can only be used with outcome");
+        assert_eq!(error("diff --step 1 foo.rs"), "--step can only be used with whence or outcome");
+        assert_eq!(error("outcome --root lib.rs foo.rs:1:1"), "--root can only be used with whence");
+        assert_eq!(error("diff -f html foo.rs"),
+                   "--format html can only be used with expand or trace, \
+                    or json with whence and outcome");
+        assert!(parse("whence -f html foo.rs:1:1").is_err());
+    }
+
+    #[test]
+    fn usage_errors_are_reported() {
+        assert_eq!(error(""), "no input file given");
+        assert_eq!(error("trace"), "no input file given");
+        assert_eq!(error("frobnicate foo.rs"), "unknown command 'frobnicate'");
+        assert_eq!(error("trace foo.rs bar.rs"),
+                   "unexpected argument 'bar.rs'; please supply a single filepath");
+        assert_eq!(error("--max-steps many foo.rs"), "--max-steps expects a number, found 'many'");
+        assert_eq!(error("-f xml foo.rs"), "unknown output format 'xml'");
+        assert_eq!(error("--order middle foo.rs"), "unknown order 'middle'");
+        assert_eq!(error("--hygiene none foo.rs"), "unknown hygiene mode 'none'");
+        assert_eq!(error("--name-template out.rs foo.rs"),
+                   "name template 'out.rs' must contain {step}");
+        assert!(error("--no-such-flag foo.rs").contains("no-such-flag"));
+    }
+
+    #[test]
+    fn positions_must_have_a_file_line_and_column() {
+        assert_eq!(parse("whence a:b.rs:2:7").unwrap().filename, "a:b.rs");
+        for arg in &["foo.rs", "foo.rs:2", ":2:7", "foo.rs:x:7", "foo.rs:2:"] {
+            assert_eq!(error(&format!("whence {}", arg)),
+                       format!("whence expects FILE:LINE:COL, found '{}'", arg));
+        }
+        assert_eq!(error("outcome foo.rs"), "outcome expects FILE:LINE:COL, found 'foo.rs'");
+    }
+}
@@ -0,0 +1,505 @@
+// Diffs between two printed steps.
+
+// Number of unchanged lines shown around each change.
+const CONTEXT: usize = 3;
+
+// Lines starting with one of these (after any `pub`) name the item in a hunk header.
+const ITEM_KEYWORDS: [&'static str; 12] = ["fn ", "struct ", "enum ", "union ", "trait ",
+                                           "impl ", "impl<", "mod ", "static ", "const ",
+                                           "type ", "macro_rules!"];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Edit {
+    Keep,
+    Delete,
+    Insert,
+}
+
+// Shortest edit script turning `old` into `new`, by Myers' O(ND) algorithm in
+// its linear-space form: the middle of the shortest path is found by searching
+// forward from the start and backward from the end at once, and the halves on
+// either side of it are diffed in turn. A step changes only a few places in a
+// file, so D is small even when the files are long.
+pub fn edit_script<T: PartialEq>(old: &[T], new: &[T]) -> Vec<Edit> {
+    let mut edits = Vec::with_capacity(::std::cmp::max(old.len(), new.len()));
+    diff_into(old, new, &mut edits);
+    edits
+}
+
+fn diff_into<T: PartialEq>(old: &[T], new: &[T], edits: &mut Vec<Edit>) {
+    let prefix = old.iter().zip(new).take_while(|&(a, b)| a == b).count();
+    let suffix = old[prefix..].iter().rev()
+                              .zip(new[prefix..].iter().rev())
+                              .take_while(|&(a, b)| a == b)
+                              .count();
+    let (old_mid, new_mid) = (&old[prefix..old.len() - suffix], &new[prefix..new.len() - suffix]);
+
+    edits.extend(vec![Edit::Keep; prefix]);
+    if old_mid.is_empty() || new_mid.is_empty() {
+        edits.extend(vec![Edit::Delete; old_mid.len()]);
+        edits.extend(vec![Edit::Insert; new_mid.len()]);
+    } else {
+        match middle(old_mid, new_mid) {
+            Some((x, y)) => {
+                diff_into(&old_mid[..x], &new_mid[..y], edits);
+                diff_into(&old_mid[x..], &new_mid[y..], edits);
+            }
+            None => {
+                edits.extend(vec![Edit::Delete; old_mid.len()]);
+                edits.extend(vec![Edit::Insert; new_mid.len()]);
+            }
+        }
+    }
+    edits.extend(vec![Edit::Keep; suffix]);
+}
+
+// A point on a shortest path from the start of `old` and `new` to their ends,
+// where the forward and backward searches meet, or `None` if they have nothing
+// in common. Both are non-empty and differ in their first and last elements,
+// so the point is neither the start nor the end.
+fn middle<T: PartialEq>(old: &[T], new: &[T]) -> Option<(usize, usize)> {
+    let (n, m) = (old.len() as isize, new.len() as isize);
+    let max_d = (n + m + 1) / 2;
+    let offset = max_d;
+    // The furthest `x` reached on each diagonal `k = x - y`, forward from the
+    // start and backward from the end, or -1 if none has been reached yet.
+    let mut forward = vec![-1isize; 2 * max_d as usize + 2];
+    let mut backward = forward.clone();
+    forward[offset as usize + 1] = 0;
+    backward[offset as usize + 1] = 0;
+    let delta = n - m;
+    // With an odd `delta` the paths meet on a forward step, otherwise on a
+    // backward one.
+    let odd = delta % 2 != 0;
+    // Diagonals that have run off the edge of either side are not searched again.
+    let (mut forward_start, mut forward_end) = (0, 0);
+    let (mut backward_start, mut backward_end) = (0, 0);
+    for d in 0..max_d {
+        let mut k = -d + forward_start;
+        while k <= d - forward_end {
+            let at = (offset + k) as usize;
+            let mut x = if k == -d || (k != d && forward[at - 1] < forward[at + 1]) {
+                forward[at + 1]
+            } else {
+                forward[at - 1] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && old[x as usize] == new[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            forward[at] = x;
+            if x > n {
+                forward_end += 2;
+            } else if y > m {
+                forward_start += 2;
+            } else if odd {
+                let other = offset + delta - k;
+                if other >= 0 && (other as usize) < backward.len() && backward[other as usize] != -1
+                   && x >= n - backward[other as usize] {
+                    return Some((x as usize, y as usize));
+                }
+            }
+            k += 2;
+        }
+
+        let mut k = -d + backward_start;
+        while k <= d - backward_end {
+            let at = (offset + k) as usize;
+            let mut x = if k == -d || (k != d && backward[at - 1] < backward[at + 1]) {
+                backward[at + 1]
+            } else {
+                backward[at - 1] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && old[(n - x - 1) as usize] == new[(m - y - 1) as usize] {
+                x += 1;
+                y += 1;
+            }
+            backward[at] = x;
+            if x > n {
+                backward_end += 2;
+            } else if y > m {
+                backward_start += 2;
+            } else if !odd {
+                let other = offset + delta - k;
+                if other >= 0 && (other as usize) < forward.len() && forward[other as usize] != -1 {
+                    let forward_x = forward[other as usize];
+                    if forward_x >= n - x {
+                        return Some((forward_x as usize, (offset + forward_x - other) as usize));
+                    }
+                }
+            }
+            k += 2;
+        }
+    }
+    None
+}
+
+// The edit script paired with the position in `old` and `new` that each edit
+// applies at.
+fn positioned(edits: &[Edit]) -> Vec<(Edit, usize, usize)> {
+    let (mut i, mut j) = (0, 0);
+    edits.iter().map(|&edit| {
+        let pos = (edit, i, j);
+        match edit {
+            Edit::Keep => {
+                i += 1;
+                j += 1;
+            }
+            Edit::Delete => i += 1,
+            Edit::Insert => j += 1,
+        }
+        pos
+    }).collect()
+}
+
+// The closest line above `line` that starts an item, like git's function
+// context in hunk headers.
+fn enclosing_item<'a>(lines: &[&'a str], line: usize) -> Option<&'a str> {
+    lines[..line].iter().rev().map(|line| line.trim()).find(|line| {
+        let rest = if line.starts_with("pub ") { &line[4..] } else { &line[..] };
+        ITEM_KEYWORDS.iter().any(|keyword| rest.starts_with(keyword))
+    })
+}
+
+// A hunk header range: 1-based start line and line count, in the style of
+// GNU diff.
+fn range(start: usize, count: usize) -> String {
+    match count {
+        0 => format!("{},0", start),
+        1 => format!("{}", start + 1),
+        _ => format!("{},{}", start + 1, count),
+    }
+}
+
+fn hunk_header(old_lines: &[&str], old_start: usize, old_count: usize,
+               new_start: usize, new_count: usize) -> String {
+    let mut header = format!("@@ -{} +{} @@", range(old_start, old_count),
+                                             range(new_start, new_count));
+    if let Some(item) = enclosing_item(old_lines, old_start) {
+        header.push(' ');
+        header.push_str(item);
+    }
+    header.push('\n');
+    header
+}
+
+fn file_header(old_name: &str, new_name: &str) -> String {
+    format!("--- {}\n+++ {}\n", old_name, new_name)
+}
+
+// Unified diff of two printed steps, with `CONTEXT` lines of context around
+// each change. Returns an empty string when the texts have the same lines.
+pub fn unified_diff(old_name: &str, new_name: &str, old: &str, new: &str) -> String {
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+    let edits = positioned(&edit_script(&old, &new));
+
+    // Group the changes into hunks, merging those whose context would overlap.
+    let mut hunks: Vec<(usize, usize)> = Vec::new();
+    for (k, &(edit, _, _)) in edits.iter().enumerate() {
+        if edit == Edit::Keep {
+            continue;
+        }
+        let start = k.saturating_sub(CONTEXT);
+        let end = ::std::cmp::min(k + 1 + CONTEXT, edits.len());
+        if let Some(hunk) = hunks.last_mut() {
+            if start <= hunk.1 {
+                hunk.1 = end;
+                continue;
+            }
+        }
+        hunks.push((start, end));
+    }
+    if hunks.is_empty() {
+        return String::new();
+    }
+
+    let mut out = file_header(old_name, new_name);
+    for (start, end) in hunks {
+        let hunk = &edits[start..end];
+        let old_count = hunk.iter().filter(|&&(edit, _, _)| edit != Edit::Insert).count();
+        let new_count = hunk.iter().filter(|&&(edit, _, _)| edit != Edit::Delete).count();
+        let (_, old_start, new_start) = hunk[0];
+        out.push_str(&hunk_header(&old, old_start, old_count, new_start, new_count));
+        for &(edit, i, j) in hunk {
+            let (prefix, line) = match edit {
+                Edit::Keep => (' ', new[j]),
+                Edit::Delete => ('-', old[i]),
+                Edit::Insert => ('+', new[j]),
+            };
+            out.push(prefix);
+            out.push_str(line);
+            out.push('\n');
+        }
+    }
+    out
+}
+
+// A token of printed source, with the line it is on and its byte offset.
+struct Token<'a> {
+    text: &'a str,
+    line: usize,
+    offset: usize,
+}
+
+impl<'a> PartialEq for Token<'a> {
+    fn eq(&self, other: &Token<'a>) -> bool {
+        self.text == other.text
+    }
+}
+
+// Split printed source into tokens: words, string literals, line comments and
+// single punctuation characters. This is coarser than the Rust lexer, but the
+// same on both sides of a diff, which is all that matters here.
+fn tokenize<'a>(text: &'a str) -> Vec<Token<'a>> {
+    let mut tokens = Vec::new();
+    let mut line_start = 0;
+    for (line, src) in text.split('\n').enumerate() {
+        let offset = line_start;
+        line_start += src.len() + 1;
+        let mut chars = src.char_indices().peekable();
+        while let Some((start, c)) = chars.next() {
+            let mut end = start + c.len_utf8();
+            if c.is_whitespace() {
+                continue;
+            } else if c.is_alphanumeric() || c == '_' {
+                while let Some(&(i, c)) = chars.peek() {
+                    if !(c.is_alphanumeric() || c == '_') {
+                        break;
+                    }
+                    end = i + c.len_utf8();
+                    chars.next();
+                }
+            } else if c == '"' {
+                let mut escaped = false;
+                while let Some((i, c)) = chars.next() {
+                    end = i + c.len_utf8();
+                    if c == '"' && !escaped {
+                        break;
+                    }
+                    escaped = c == '\\' && !escaped;
+                }
+            } else if c == '/' && src[end..].starts_with('/') {
+                end = src.len();
+                while chars.next().is_some() {}
+            }
+            tokens.push(Token { text: &src[start..end], line: line, offset: offset + start });
+        }
+    }
+    tokens
+}
+
+// Diff of the tokens of two printed steps, so that changes in how the pretty
+// printer breaks lines are ignored. Each change is printed as the removed and
+// inserted tokens, under a header giving the lines they span.
+pub fn token_diff(old_name: &str, new_name: &str, old: &str, new: &str) -> String {
+    let old_tokens = tokenize(old);
+    let new_tokens = tokenize(new);
+    let old_lines: Vec<&str> = old.lines().collect();
+    let edits = positioned(&edit_script(&old_tokens, &new_tokens));
+
+    let mut out = String::new();
+    let mut k = 0;
+    while k < edits.len() {
+        if edits[k].0 == Edit::Keep {
+            k += 1;
+            continue;
+        }
+        let (_, old_at, new_at) = edits[k];
+        let (mut removed, mut inserted) = (Vec::new(), Vec::new());
+        while k < edits.len() && edits[k].0 != Edit::Keep {
+            match edits[k] {
+                (Edit::Delete, i, _) => removed.push(&old_tokens[i]),
+                (Edit::Insert, _, j) => inserted.push(&new_tokens[j]),
+                (Edit::Keep, _, _) => unreachable!(),
+            }
+            k += 1;
+        }
+
+        // Anchor the change on the lines its tokens are on, or for a pure
+        // insertion or deletion, after the line of the following token.
+        let line_of = |tokens: &Vec<Token>, at: usize| {
+            tokens.get(at).or(tokens.last()).map_or(0, |token| token.line + 1)
+        };
+        let span = |changed: &Vec<&Token>| {
+            changed.first().map(|first| (first.line, changed.last().unwrap().line - first.line + 1))
+        };
+        let (old_start, old_count) = span(&removed).unwrap_or((line_of(&old_tokens, old_at), 0));
+        let (new_start, new_count) = span(&inserted).unwrap_or((line_of(&new_tokens, new_at), 0));
+
+        if out.is_empty() {
+            out.push_str(&file_header(old_name, new_name));
+        }
+        out.push_str(&hunk_header(&old_lines, old_start, old_count, new_start, new_count));
+        for &(prefix, ref tokens) in &[('-', removed), ('+', inserted)] {
+            if tokens.is_empty() {
+                continue;
+            }
+            let texts: Vec<&str> = tokens.iter().map(|token| token.text).collect();
+            out.push(prefix);
+            out.push_str(&texts.join(" "));
+            out.push('\n');
+        }
+    }
+    out
+}
+
+// The byte offset in `new` of the code at byte offset `at` in `old`, found by
+// diffing their tokens: the same place in the same token when the token at `at`
+// is kept, or else the start of the nearest kept token before it. `None` when
+// no token up to `at` is kept.
+pub fn map_offset(old: &str, new: &str, at: usize) -> Option<usize> {
+    let old_tokens = tokenize(old);
+    let new_tokens = tokenize(new);
+    let mut kept = None;
+    for (edit, i, j) in positioned(&edit_script(&old_tokens, &new_tokens)) {
+        if edit == Edit::Insert {
+            continue;
+        }
+        if old_tokens[i].offset > at {
+            break;
+        }
+        if edit == Edit::Keep {
+            kept = Some((&old_tokens[i], &new_tokens[j]));
+        }
+    }
+    kept.map(|(old, new)| {
+        let within = at - old.offset;
+        new.offset + if within < old.text.len() { within } else { 0 }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{edit_script, map_offset, token_diff, unified_diff, Edit};
+
+    // `old` with `edits` applied, taking inserted elements from `new` in turn.
+    fn apply(old: &[char], new: &[char], edits: &[Edit]) -> Vec<char> {
+        let (mut i, mut j, mut out) = (0, 0, Vec::new());
+        for &edit in edits {
+            match edit {
+                Edit::Keep => {
+                    assert_eq!(old[i], new[j]);
+                    out.push(old[i]);
+                    i += 1;
+                    j += 1;
+                }
+                Edit::Delete => i += 1,
+                Edit::Insert => {
+                    out.push(new[j]);
+                    j += 1;
+                }
+            }
+        }
+        assert_eq!((i, j), (old.len(), new.len()));
+        out
+    }
+
+    fn changes(edits: &[Edit]) -> usize {
+        edits.iter().filter(|&&edit| edit != Edit::Keep).count()
+    }
+
+    fn check(old: &str, new: &str, expected_changes: usize) {
+        let (old, new): (Vec<char>, Vec<char>) = (old.chars().collect(), new.chars().collect());
+        let edits = edit_script(&old, &new);
+        assert_eq!(apply(&old, &new, &edits), new);
+        assert_eq!(changes(&edits), expected_changes);
+    }
+
+    #[test]
+    fn edit_script_is_shortest() {
+        check("", "", 0);
+        check("abc", "abc", 0);
+        check("", "abc", 3);
+        check("abc", "", 3);
+        check("abc", "xyz", 6);
+        check("abcabba", "cbabac", 5);
+        check("abcdef", "abxdef", 2);
+        check("xabc", "abcx", 2);
+        check("ab", "ba", 2);
+        check("a", "b", 2);
+        check("aaaa", "aa", 2);
+    }
+
+    #[test]
+    fn edit_script_handles_long_inputs_with_few_changes() {
+        let old: Vec<usize> = (0..200000).collect();
+        let mut new = old.clone();
+        new[1000] = 0;
+        new.insert(150000, 7);
+        new.remove(199000);
+        let edits = edit_script(&old, &new);
+        assert_eq!(changes(&edits), 4);
+        assert_eq!(edits.len(), old.len() + 2);
+    }
+
+    #[test]
+    fn unified_diff_of_equal_texts_is_empty() {
+        assert_eq!(unified_diff("a", "b", "x\ny\n", "x\ny\n"), "");
+    }
+
+    #[test]
+    fn unified_diff_shows_context() {
+        let old = "fn f() {\n    a;\n    b;\n    c;\n    d;\n    e;\n}\n";
+        let new = "fn f() {\n    a;\n    b;\n    x;\n    d;\n    e;\n}\n";
+        assert_eq!(unified_diff("old", "new", old, new),
+                   "--- old\n+++ new\n\
+                    @@ -1,7 +1,7 @@\n \
+                    fn f() {\n     a;\n     b;\n-    c;\n+    x;\n     d;\n     e;\n }\n");
+    }
+
+    #[test]
+    fn unified_diff_names_the_item_a_hunk_starts_in() {
+        let old = concat!("impl S {\n    fn f(&self) {\n",
+                          "        a;\n        b;\n        c;\n        d;\n        e;\n",
+                          "    }\n}\n");
+        let new = old.replace("e;", "x;");
+        let diff = unified_diff("old", "new", old, &new);
+        assert_eq!(diff.lines().nth(2), Some("@@ -4,6 +4,6 @@ fn f(&self) {"));
+
+        let old = "impl S {\n    // one\n    // two\n    // three\n    // four\n    fn g() {}\n}\n";
+        let new = old.replace("four", "4");
+        let diff = unified_diff("old", "new", old, &new);
+        assert_eq!(diff.lines().nth(2), Some("@@ -2,6 +2,6 @@ impl S {"));
+    }
+
+    #[test]
+    fn unified_diff_keeps_distant_changes_in_separate_hunks() {
+        let old: Vec<String> = (0..20).map(|i| format!("line {}", i)).collect();
+        let mut new = old.clone();
+        new[2] = "changed".to_owned();
+        new.insert(15, "added".to_owned());
+        let diff = unified_diff("old", "new", &old.join("\n"), &new.join("\n"));
+        let headers: Vec<&str> = diff.lines().filter(|line| line.starts_with("@@")).collect();
+        assert_eq!(headers, ["@@ -1,6 +1,6 @@", "@@ -13,6 +13,7 @@"]);
+    }
+
+    #[test]
+    fn unified_diff_counts_a_pure_insertion_from_the_line_before() {
+        assert_eq!(unified_diff("old", "new", "a\n", "a\nb\n"),
+                   "--- old\n+++ new\n@@ -1 +1,2 @@\n a\n+b\n");
+        assert_eq!(unified_diff("old", "new", "", "a\n"),
+                   "--- old\n+++ new\n@@ -0,0 +1 @@\n+a\n");
+    }
+
+    #[test]
+    fn offsets_map_to_the_same_token_across_line_breaks_and_comments() {
+        let old = "fn f() {\n    g(a,\n      b);\n}\n";
+        let new = "// heading\nfn f() { g(a, b); }\n";
+        assert_eq!(map_offset(old, new, old.find('b').unwrap()), new.find('b'));
+        assert_eq!(map_offset(old, new, old.find("f(").unwrap()), new.find("f("));
+        // From the whitespace after a token, to the start of that token.
+        assert_eq!(map_offset(old, new, old.find(",\n").unwrap() + 1), new.find(','));
+        assert_eq!(map_offset("x#3 + 1", "x + 1", 1), Some(0));
+        assert_eq!(map_offset("a b", "c d", 2), None);
+    }
+
+    #[test]
+    fn token_diff_ignores_line_breaks() {
+        assert_eq!(token_diff("old", "new", "f(a, b);\n", "f(a,\n  b);\n"), "");
+        assert_eq!(token_diff("old", "new", "let x = foo!(2);\n", "let x = 1 + 1;\n"),
+                   "--- old\n+++ new\n@@ -1 +1 @@\n-foo ! ( 2 )\n+1 + 1\n");
+    }
+}
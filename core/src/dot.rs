@@ -0,0 +1,58 @@
+// Graphviz DOT export of the expansion tree: one node per invocation, with an
+// edge from each expansion to the invocations its output contained, and the
+// invocations expanded in the same step clustered together.
+
+use events::{Event, SpanInfo};
+
+use std::collections::BTreeMap;
+
+fn quote(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' | '\\' => {
+                out.push('\\');
+                out.push(c);
+            }
+            '\n' => out.push_str("\\n"),
+            _ => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn location(span: &SpanInfo) -> String {
+    format!("{}:{}:{}", span.file, span.lo.line, span.lo.col)
+}
+
+pub fn render(filename: &str, events: &[Event]) -> String {
+    let mut out = String::from("digraph expansion {\n");
+    out.push_str("    node [shape=box, fontname=monospace];\n");
+    out.push_str(&format!("    root [label={}, shape=folder];\n", quote(filename)));
+
+    let mut steps: BTreeMap<usize, Vec<&Event>> = BTreeMap::new();
+    for event in events {
+        steps.entry(event.step).or_insert(Vec::new()).push(event);
+    }
+    for (step, events) in &steps {
+        out.push_str(&format!("    subgraph cluster_step{} {{\n", step));
+        out.push_str(&format!("        label={};\n", quote(&format!("step {}", step))));
+        out.push_str("        style=dashed;\n");
+        for event in events {
+            let label = format!("{}\n{}", event.name, location(&event.call_site));
+            out.push_str(&format!("        n{} [label={}];\n", event.id, quote(&label)));
+        }
+        out.push_str("    }\n");
+    }
+
+    for event in events {
+        match event.parent {
+            Some(parent) => out.push_str(&format!("    n{} -> n{};\n", parent, event.id)),
+            None => out.push_str(&format!("    root -> n{};\n", event.id)),
+        }
+    }
+    out.push_str("}\n");
+    out
+}
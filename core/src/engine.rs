@@ -0,0 +1,548 @@
+// The stepping engine shared by the backends: handles each step of a session
+// expanding the crate through a `Backend`, according to the command.
+
+use backtrace::Frame;
+use cli::{Command, Options, OutputFormat};
+use diff;
+use dot;
+use events::{self, Diagnostic, Event, SpanInfo};
+use html::{self, Origins};
+use markers;
+use outcome::Target;
+use output::{self, OutputDir};
+use session::{ExpansionStep, Steps};
+use term;
+
+use std::any::Any;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+// An invocation waiting to be expanded.
+pub struct Invocation {
+    // The macro, with its `!`.
+    pub name: String,
+    // Where it is, as `file:line:col`.
+    pub location: String,
+    // The expansions it came from, innermost first.
+    pub backtrace: Vec<Frame>,
+}
+
+// A crate being expanded one step at a time, through one of the `syntax` crates.
+pub trait Backend {
+    // The crate's AST, as the `syntax` crate has it.
+    type Crate;
+
+    // The crate at the current step.
+    fn krate(&self) -> &Self::Crate;
+
+    // The root file of the crate.
+    fn filename(&self) -> &str;
+
+    // The number of steps expanded so far.
+    fn index(&self) -> usize;
+
+    // Every expansion seen so far.
+    fn events(&self) -> &[Event];
+
+    // The invocations and definitions left unexpanded so far, for backends that
+    // go on expanding the rest of the crate past them.
+    fn diagnostics(&self) -> &[Diagnostic] {
+        &[]
+    }
+
+    // The invocations left to expand in the current step, in source order.
+    fn pending(&mut self) -> Vec<Invocation>;
+
+    // Whether the events record the `macro_rules!` arm each invocation matched,
+    // which `--rules` reports.
+    fn reports_rules(&self) -> bool {
+        false
+    }
+
+    // Called before each step is expanded, once the limits have been checked.
+    fn prepare(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+
+    // Where the definition of the `i`th pending invocation's macro is, when known.
+    fn definition_site(&self, _i: usize) -> Option<String> {
+        None
+    }
+
+    // Expand every pending invocation, returning the indices of those left
+    // unexpanded, for a later step or with a diagnostic, or why the step could
+    // not be expanded.
+    fn expand_all(&mut self) -> Result<HashSet<usize>, String>;
+
+    // Expand only the `chosen`th pending invocation, returning whether it was
+    // expanded rather than left as it is with a diagnostic.
+    fn expand_one(&mut self, chosen: usize) -> Result<bool, String>;
+
+    // The crate at the current step, one (filename, source) pair per module file.
+    fn print_step(&self) -> Result<Vec<(String, String)>, String>;
+
+    // The whole crate at the current step, with the code that came from a macro
+    // marked with the index in `origins` of the invocation in `source`, the
+    // root file's contents, that it came from.
+    fn print_origins(&self, source: &str, origins: &mut Origins) -> Result<String, String>;
+
+    // The whole crate at the current step, with the code that came from a macro
+    // marked with the id of the event of the innermost expansion that produced
+    // it and the side of that invocation it was written on.
+    fn print_outputs(&self) -> Result<String, String>;
+}
+
+// Run `f`, returning `Err` if it panics with a `P`, as the `syntax` crates'
+// expanders do to give up once they have reported an error. That panic is not
+// reported again; any other goes through as usual.
+pub fn catch_fatal<P: Any, T, F: FnOnce() -> T>(f: F) -> Result<T, ()> {
+    let hook = Arc::new(panic::take_hook());
+    let previous = hook.clone();
+    panic::set_hook(Box::new(move |info| {
+        if !info.payload().is::<P>() {
+            previous(info);
+        }
+    }));
+    let result = panic::catch_unwind(AssertUnwindSafe(f));
+    drop(panic::take_hook());
+    if let Ok(hook) = Arc::try_unwrap(hook) {
+        panic::set_hook(hook);
+    }
+    result.map_err(|payload| {
+        if !payload.is::<P>() {
+            panic::resume_unwind(payload);
+        }
+    })
+}
+
+// Module files keep their position relative to the root file inside the output directory.
+fn output_path<B: Backend>(data: &B, filename: &str, output: &OutputDir) -> PathBuf {
+    let path = Path::new(filename);
+    let stem = path.file_stem().and_then(|stem| stem.to_str()).unwrap_or("");
+    let parent = path.parent().unwrap_or(Path::new(""));
+    let root = Path::new(data.filename()).parent().unwrap_or(Path::new(""));
+    output.path_for(parent.strip_prefix(root).unwrap_or(Path::new("")), stem, data.index())
+}
+
+// Write every module file of the current step, returning the paths written.
+fn write_files<B: Backend>(data: &B,
+                           files: &[(String, String)],
+                           output: &OutputDir,
+                           header: Option<&str>) -> Result<Vec<PathBuf>, String> {
+    let mut written = Vec::new();
+    for &(ref filename, ref text) in files {
+        let path = output_path(data, filename, output);
+        try!(output.claim(&path));
+        try!(File::create(&path).and_then(|mut file| {
+            if let Some(header) = header {
+                try!(writeln!(file, "{}", header));
+            }
+            file.write_all(text.as_bytes())
+        }).map_err(|e| format!("could not write {}: {}", path.display(), e)));
+        written.push(path);
+    }
+    Ok(written)
+}
+
+// The comment that introduces a step, naming what was expanded to reach it
+// when expanding one invocation per step.
+fn heading<B: Backend>(options: &Options, data: &B, expanded: &[String]) -> String {
+    if options.one_by_one && !expanded.is_empty() {
+        format!("// Step {}: expanded {}", data.index(), expanded.join(", "))
+    } else {
+        format!("// Step {}", data.index())
+    }
+}
+
+// Comment lines naming the arm that each invocation expanded to reach the
+// current step matched, with its bindings and the arms that failed first.
+fn rules<B: Backend>(options: &Options, data: &B) -> Vec<String> {
+    let mut lines = Vec::new();
+    if !options.rules {
+        return lines;
+    }
+    for event in data.events().iter().filter(|event| event.step == data.index()) {
+        let rule = match event.rule {
+            Some(ref rule) => rule,
+            None => continue,
+        };
+        lines.push(format!("// {} at {}:{}:{} matched arm {}: {}",
+                           event.name,
+                           event.call_site.file,
+                           event.call_site.lo.line,
+                           event.call_site.lo.col,
+                           rule.arm,
+                           rule.matcher));
+        for binding in &rule.bindings {
+            lines.push(format!("//     ${}:{} = {}", binding.name, binding.fragment, binding.value));
+        }
+        for failure in &rule.failed {
+            let at = match failure.at {
+                Some(ref at) => format!(" at {}:{}:{}", at.file, at.lo.line, at.lo.col),
+                None => String::new(),
+            };
+            lines.push(format!("//   arm {} failed{}: found {}, expected {}: {}",
+                               failure.arm, at, failure.found, failure.expected, failure.matcher));
+        }
+    }
+    lines
+}
+
+// The comment lines `expand` writes at the top of each step file.
+fn file_header<B: Backend>(options: &Options, data: &B, expanded: &[String]) -> Vec<String> {
+    let mut header = rules(options, data);
+    if options.one_by_one {
+        header.insert(0, heading(options, data, expanded));
+    }
+    header
+}
+
+// The steps collected for the HTML viewer, which is written once they are all known.
+struct HtmlTrace {
+    source: String,
+    origins: Origins,
+    steps: Vec<html::Step>,
+    // Every invocation expanded over the run, for the manifest.
+    expanded: Vec<String>,
+}
+
+// The steps collected for the JSON trace, and where each expansion's output
+// is in the step it produced, by event id.
+struct JsonTrace {
+    steps: Vec<events::Step>,
+    outputs: HashMap<usize, SpanInfo>,
+}
+
+// Handles each step as it is produced, according to the chosen command.
+struct StepWriter<'o> {
+    options: &'o Options,
+    output: Option<OutputDir>,
+    html: Option<HtmlTrace>,
+    json: Option<JsonTrace>,
+    // The files written for the latest step.
+    written: Vec<PathBuf>,
+    previous: Option<Vec<(String, String)>>,
+    // The invocations pending at each step, which the next step expands.
+    pending: Vec<Vec<String>>,
+}
+
+impl<'o> StepWriter<'o> {
+    fn new(options: &'o Options) -> Result<StepWriter<'o>, String> {
+        let output = if options.command == Command::Expand {
+            Some(try!(OutputDir::new(options.output_dir(),
+                                     options.name_template.clone(),
+                                     options.force,
+                                     &options.filename)))
+        } else {
+            None
+        };
+        let html = if options.format == OutputFormat::Html {
+            let mut source = String::new();
+            try!(File::open(&options.filename).and_then(|mut f| f.read_to_string(&mut source))
+                 .map_err(|e| format!("could not read {}: {}", options.filename, e)));
+            Some(HtmlTrace {
+                source: source,
+                origins: Origins::default(),
+                steps: Vec::new(),
+                expanded: Vec::new(),
+            })
+        } else {
+            None
+        };
+        let json = if options.format == OutputFormat::Json {
+            Some(JsonTrace { steps: Vec::new(), outputs: HashMap::new() })
+        } else {
+            None
+        };
+        Ok(StepWriter {
+            options: options,
+            output: output,
+            html: html,
+            json: json,
+            written: Vec::new(),
+            previous: None,
+            pending: Vec::new(),
+        })
+    }
+
+    // The changes made to `filename` by step `index`.
+    fn diff(&self, filename: &str, index: usize, old: &str, new: &str) -> String {
+        let old_name = format!("{} (step {})", filename, index - 1);
+        let new_name = format!("{} (step {})", filename, index);
+        if self.options.diff_tokens {
+            diff::token_diff(&old_name, &new_name, old, new)
+        } else {
+            diff::unified_diff(&old_name, &new_name, old, new)
+        }
+    }
+
+    fn step<B: Backend>(&mut self, data: &B, step: ExpansionStep) -> Result<(), String> {
+        let ExpansionStep { files, expanded, pending, .. } = step;
+        self.pending.push(pending);
+        if self.html.is_some() {
+            return self.html_step(data, expanded);
+        }
+        if self.json.is_some() {
+            return self.json_step(data, expanded);
+        }
+        if self.options.format == OutputFormat::Dot {
+            // The graph is built from the events once every step is done.
+            return Ok(());
+        }
+        match self.options.command {
+            Command::Expand => {
+                let header = file_header(self.options, data, &expanded);
+                let header = if header.is_empty() { None } else { Some(header.join("\n")) };
+                let output = self.output.as_mut().unwrap();
+                self.written = try!(write_files(data, &files, output, header.as_ref().map(|h| &h[..])));
+                output.record(data.index(), &self.written, expanded)
+            }
+            Command::Trace if self.options.color => {
+                let text = try!(data.print_outputs());
+                println!("{}: {}", heading(self.options, data, &expanded), data.filename());
+                for line in rules(self.options, data) {
+                    println!("{}", line);
+                }
+                println!("{}", term::colorize(&text));
+                Ok(())
+            }
+            Command::Trace => {
+                let rules = rules(self.options, data);
+                for (i, (filename, text)) in files.into_iter().enumerate() {
+                    println!("{}: {}", heading(self.options, data, &expanded), filename);
+                    if i == 0 {
+                        for line in &rules {
+                            println!("{}", line);
+                        }
+                    }
+                    println!("{}", text);
+                }
+                Ok(())
+            }
+            Command::Diff => {
+                if let Some(ref previous) = self.previous {
+                    println!("{}", heading(self.options, data, &expanded));
+                    for line in rules(self.options, data) {
+                        println!("{}", line);
+                    }
+                    for (&(ref filename, ref text), &(_, ref old)) in files.iter().zip(previous) {
+                        print!("{}", self.diff(filename, data.index(), old, text));
+                    }
+                }
+                self.previous = Some(files);
+                Ok(())
+            }
+            Command::Stats | Command::Clean | Command::Whence | Command::Outcome => Ok(()),
+        }
+    }
+
+    // Where the latest step can be found, for error reports.
+    fn last_step<B: Backend>(&self, data: &B) -> String {
+        let files: Vec<String> = self.written.iter().map(|path| path.display().to_string()).collect();
+        match files.len() {
+            0 => format!("step {}", data.index()),
+            _ => format!("step {}, written to {}", data.index(), files.join(", ")),
+        }
+    }
+
+    // Print the step with markers around expanded code, to be shown once every
+    // step has been seen.
+    fn html_step<B: Backend>(&mut self, data: &B, expanded: Vec<String>) -> Result<(), String> {
+        let heading = heading(self.options, data, &expanded);
+        let trace = self.html.as_mut().unwrap();
+        let text = try!(data.print_origins(&trace.source, &mut trace.origins));
+        trace.steps.push(html::Step { heading: heading, text: text });
+        trace.expanded.extend(expanded);
+        Ok(())
+    }
+
+    // Print the step with markers around the code that came from a macro, to
+    // find where the code each expansion of this step produced is in the
+    // printed text, and which of it was written on each side of its invocation.
+    fn json_step<B: Backend>(&mut self, data: &B, expanded: Vec<String>) -> Result<(), String> {
+        let text = try!(data.print_outputs());
+        let (text, ranges, segments) = markers::strip_markers(&text);
+        let stem = Path::new(data.filename()).file_stem()
+                   .and_then(|stem| stem.to_str()).unwrap_or("");
+        let file = output::apply_template(&self.options.name_template, stem, data.index());
+        let trace = self.json.as_mut().unwrap();
+        for (id, (lo, hi)) in ranges {
+            if data.events()[id].step != data.index() {
+                continue;
+            }
+            trace.outputs.insert(id, SpanInfo {
+                file: file.clone(),
+                lo: events::position(&text, lo),
+                hi: events::position(&text, hi),
+            });
+        }
+        let regions = segments.iter().map(|segment| {
+            events::Region {
+                event: segment.id,
+                side: segment.side.name().to_owned(),
+                lo: events::position(&text, segment.lo),
+                hi: events::position(&text, segment.hi),
+            }
+        }).collect();
+        trace.steps.push(events::Step {
+            step: data.index(),
+            expanded: expanded,
+            text: text,
+            regions: regions,
+        });
+        Ok(())
+    }
+
+    // Write a trace covering the whole run, or print it for `trace`.
+    fn write_trace<B: Backend>(&mut self,
+                   data: &B,
+                   extension: &str,
+                   contents: &str,
+                   expanded: Vec<String>) -> Result<(), String> {
+        if self.options.command != Command::Expand {
+            print!("{}", contents);
+            return Ok(());
+        }
+        let output = self.output.as_mut().unwrap();
+        let stem = Path::new(data.filename()).file_stem()
+                   .and_then(|stem| stem.to_str()).unwrap_or("");
+        let path = output.file_path(&format!("{}Trace.{}", stem, extension));
+        try!(output.claim(&path));
+        try!(File::create(&path).and_then(|mut file| file.write_all(contents.as_bytes()))
+             .map_err(|e| format!("could not write {}: {}", path.display(), e)));
+        self.written = vec![path];
+        output.record(data.index(), &self.written, expanded)
+    }
+
+    fn finish<B: Backend>(&mut self, data: &B) -> Result<(), String> {
+        let trace = if let Some(ref trace) = self.html {
+            let page = html::render(data.filename(), &trace.source, &trace.origins, &trace.steps);
+            Some(("html", page, trace.expanded.clone()))
+        } else if let Some(ref mut trace) = self.json {
+            let mut events = data.events().to_vec();
+            for event in &mut events {
+                event.output = trace.outputs.get(&event.id).cloned();
+            }
+            let expanded = trace.steps.iter().flat_map(|step| step.expanded.clone()).collect();
+            let steps = mem::replace(&mut trace.steps, Vec::new());
+            let json = events::Trace {
+                file: data.filename().to_string(),
+                steps: steps,
+                events: events,
+                diagnostics: data.diagnostics().to_vec(),
+            };
+            Some(("json", json.to_json(), expanded))
+        } else if self.options.format == OutputFormat::Dot {
+            let expanded = data.events().iter().map(|event| event.name.clone()).collect();
+            Some(("dot", dot::render(data.filename(), data.events()), expanded))
+        } else {
+            None
+        };
+        if let Some((extension, contents, expanded)) = trace {
+            return self.write_trace(data, extension, &contents, expanded);
+        }
+
+        if self.options.command != Command::Stats {
+            return Ok(());
+        }
+        println!("file: {}", data.filename());
+        println!("steps: {}", data.index());
+        for (i, macs) in self.pending.iter().enumerate() {
+            println!("step {}: {} pending invocation(s)", i, macs.len());
+        }
+        if !data.diagnostics().is_empty() {
+            println!("left unexpanded: {}", data.diagnostics().len());
+        }
+        Ok(())
+    }
+}
+
+// Step through to the step `whence` or `outcome` asks about, and print where
+// the code at its position came from, or what its invocation expands to.
+fn query<B: Backend>(options: &Options, steps: &mut Steps<B>) -> Result<(), String> {
+    let mut found = false;
+    let mut expanded = Vec::new();
+    while let Some(step) = steps.next() {
+        for diagnostic in &step.diagnostics {
+            let _ = writeln!(io::stderr(), "{}", diagnostic.report());
+        }
+        expanded = step.expanded;
+        if options.step == Some(step.index) {
+            found = true;
+            break;
+        }
+    }
+    if let Some(error) = steps.error() {
+        return Err(error.report(&format!("step {}", steps.backend().index())));
+    }
+    if let Some(step) = options.step {
+        if !found {
+            return Err(format!("step {} was asked for, but the last step is {}",
+                               step, steps.backend().index()));
+        }
+    }
+    let json = options.format == OutputFormat::Json;
+    if options.command == Command::Whence {
+        // Positions count the comments `expand` writes at the top of each step
+        // file with the same options.
+        let (line, col) = options.position.unwrap();
+        let file = options.module_file.as_ref().unwrap();
+        let header = file_header(options, steps.backend(), &expanded).len();
+        if line <= header {
+            return Err(format!("{}:{} is in the comments at the top of step {}",
+                               file, line, steps.backend().index()));
+        }
+        let mut whence = try!(steps.whence(file, line - header, col));
+        whence.line = line;
+        if json {
+            print!("{}", whence.to_json());
+        } else {
+            println!("{}", whence.report());
+        }
+    } else {
+        let target = match options.invocation {
+            Some(n) => Target::Index(n),
+            None => {
+                let (line, col) = options.position.unwrap();
+                Target::Position(line, col)
+            }
+        };
+        let outcome = try!(steps.outcome(target));
+        if json {
+            print!("{}", outcome.to_json());
+        } else {
+            println!("{}", outcome.report());
+        }
+    }
+    Ok(())
+}
+
+// Handle every step of `steps` until no invocations are left.
+pub fn run<B: Backend>(options: &Options, steps: &mut Steps<B>) -> Result<(), String> {
+    if options.rules && !steps.backend().reports_rules() {
+        return Err("--rules is unsupported by this backend, which does not match invocations \
+                    itself; use stable-expander".to_owned());
+    }
+    if options.command == Command::Whence || options.command == Command::Outcome {
+        return query(options, steps);
+    }
+    let mut writer = try!(StepWriter::new(options));
+    while let Some(step) = steps.next() {
+        for diagnostic in &step.diagnostics {
+            let _ = writeln!(io::stderr(), "{}", diagnostic.report());
+        }
+        try!(writer.step(steps.backend(), step));
+    }
+    // Steps that ended early are still handled as a whole run, so that the
+    // steps before are kept.
+    try!(writer.finish(steps.backend()));
+    match steps.error() {
+        Some(error) => Err(error.report(&writer.last_step(steps.backend()))),
+        None => Ok(()),
+    }
+}
@@ -0,0 +1,195 @@
+// Machine-readable record of every expansion, for the JSON trace.
+
+use rustc_serialize::json;
+
+use markers::Side;
+
+// A 1-based line and column.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Position {
+    pub line: usize,
+    pub col: usize,
+}
+
+encodable!(Position { line, col });
+
+#[derive(Clone)]
+pub struct SpanInfo {
+    pub file: String,
+    pub lo: Position,
+    pub hi: Position,
+}
+
+encodable!(SpanInfo { file, lo, hi });
+
+impl SpanInfo {
+    // Whether `other` lies within `self`.
+    pub fn contains(&self, other: &SpanInfo) -> bool {
+        self.file == other.file && self.lo <= other.lo && other.hi <= self.hi
+    }
+}
+
+// One macro invocation being expanded.
+#[derive(Clone)]
+pub struct Event {
+    pub id: usize,
+    // The step the expansion produced.
+    pub step: usize,
+    // The macro, with its `!`.
+    pub name: String,
+    pub call_site: SpanInfo,
+    // Where the macro is defined, when known.
+    pub callee: Option<SpanInfo>,
+    // Where the code it expanded to is in the printed step, once printed.
+    pub output: Option<SpanInfo>,
+    // The expansion that produced the invocation, if it did not come from
+    // the input file.
+    pub parent: Option<usize>,
+    // The expansion of the invocation in the input file that this one came
+    // from through its parents, or its own id if it is one.
+    pub root: usize,
+    // How the invocation matched its `macro_rules!` definition, for backends
+    // that match invocations themselves.
+    pub rule: Option<RuleMatch>,
+}
+
+encodable!(Event { id, step, name, call_site, callee, output, parent, root, rule });
+
+// Which side of its invocation the code at `span`, which the expansion
+// `events[id]` produced, was written on: the arguments when it lies within the
+// call site of that expansion or of one it is nested in, the macro's body
+// otherwise.
+pub fn side(events: &[Event], id: usize, span: &SpanInfo) -> Side {
+    let mut expn = Some(id);
+    while let Some(id) = expn {
+        if events[id].call_site.contains(span) {
+            return Side::CallSite;
+        }
+        expn = events[id].parent;
+    }
+    Side::Callee
+}
+
+// The arm of a `macro_rules!` definition an invocation matched.
+#[derive(Clone)]
+pub struct RuleMatch {
+    // The arm, counting from 0, and its matcher as written.
+    pub arm: usize,
+    pub matcher: String,
+    // Every metavariable of the matcher, in the order they are written.
+    pub bindings: Vec<Binding>,
+    // The arms before it, none of which matched.
+    pub failed: Vec<ArmFailure>,
+}
+
+encodable!(RuleMatch { arm, matcher, bindings, failed });
+
+#[derive(Clone)]
+pub struct Binding {
+    pub name: String,
+    pub fragment: String,
+    // The tokens it matched. A metavariable inside a repetition has a list,
+    // `[a, b]`, with one more level of nesting for each repetition.
+    pub value: String,
+}
+
+encodable!(Binding { name, fragment, value });
+
+#[derive(Clone)]
+pub struct ArmFailure {
+    pub arm: usize,
+    pub matcher: String,
+    // Where matching stopped: the token found there, e.g. "the token `,`",
+    // and what the arm expected instead.
+    pub found: String,
+    pub expected: String,
+    pub at: Option<SpanInfo>,
+}
+
+encodable!(ArmFailure { arm, matcher, found, expected, at });
+
+// An invocation or definition that could not be expanded, and was left as it
+// is while the rest of the crate went on expanding.
+#[derive(Clone)]
+pub struct Diagnostic {
+    // The step it was left in.
+    pub step: usize,
+    // The macro, with its `!`.
+    pub name: String,
+    pub span: SpanInfo,
+    pub message: String,
+    // For an invocation that no arm matched, the arm that got furthest.
+    pub closest: Option<ArmFailure>,
+}
+
+encodable!(Diagnostic { step, name, span, message, closest });
+
+impl Diagnostic {
+    // The warning printed for it.
+    pub fn report(&self) -> String {
+        let mut report = format!("warning: {} at {}:{}:{} left unexpanded in step {}: {}",
+                                 self.name,
+                                 self.span.file,
+                                 self.span.lo.line,
+                                 self.span.lo.col,
+                                 self.step,
+                                 self.message);
+        if let Some(ref closest) = self.closest {
+            report.push_str(&format!("\n  closest arm {}: {}", closest.arm, closest.matcher));
+            let at = match closest.at {
+                Some(ref at) => format!(" at {}:{}:{}", at.file, at.lo.line, at.lo.col),
+                None => String::new(),
+            };
+            report.push_str(&format!("\n  matching stopped{}: found {}, expected {}",
+                                     at, closest.found, closest.expected));
+        }
+        report
+    }
+}
+
+pub struct Step {
+    pub step: usize,
+    // The invocations expanded to produce this step.
+    pub expanded: Vec<String>,
+    // The printed step, which the `output` spans of events refer to.
+    pub text: String,
+    // The code in `text` that came from a macro, in order.
+    pub regions: Vec<Region>,
+}
+
+encodable!(Step { step, expanded, text, regions });
+
+// A stretch of a printed step that one expansion produced, written on one side
+// of its invocation, `callee` or `call_site`.
+pub struct Region {
+    pub event: usize,
+    pub side: String,
+    pub lo: Position,
+    pub hi: Position,
+}
+
+encodable!(Region { event, side, lo, hi });
+
+pub struct Trace {
+    pub file: String,
+    pub steps: Vec<Step>,
+    pub events: Vec<Event>,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+encodable!(Trace { file, steps, events, diagnostics });
+
+impl Trace {
+    pub fn to_json(&self) -> String {
+        format!("{}\n", json::as_pretty_json(self))
+    }
+}
+
+// The position of byte `offset` in `text`.
+pub fn position(text: &str, offset: usize) -> Position {
+    let before = &text[..offset];
+    Position {
+        line: before.matches('\n').count() + 1,
+        col: before.rsplit('\n').next().unwrap_or("").chars().count() + 1,
+    }
+}
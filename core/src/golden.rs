@@ -0,0 +1,163 @@
+// Golden-file tests over the fixtures in the `tests/` directory: every `.rs`
+// file directly in it is a fixture, so a new one is picked up as it is added.
+// Each backend's harness steps through every fixture and compares each step
+// file, named as `expand` names it, with the one checked in under
+// `tests/expected/BACKEND/FIXTURE/`. With `--bless` the expected files are
+// written instead.
+//
+// The libsyntax backends print through the pretty-printer of the nightly
+// compiler they are built against, so their expected files hold for one
+// toolchain only, and their harnesses are behind the `golden` feature.
+
+use diff;
+use output::{self, DEFAULT_TEMPLATE};
+
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+// The fixtures in `dir`, in name order.
+fn fixtures(dir: &Path) -> Result<Vec<PathBuf>, String> {
+    let entries = try!(fs::read_dir(dir).map_err(|e| format!("could not read {}: {}", dir.display(), e)));
+    let mut fixtures = Vec::new();
+    for entry in entries {
+        let path = try!(entry.map_err(|e| format!("could not read {}: {}", dir.display(), e))).path();
+        if path.is_file() && path.extension().map_or(false, |ext| ext == "rs") {
+            fixtures.push(path);
+        }
+    }
+    fixtures.sort();
+    Ok(fixtures)
+}
+
+// The files of every step of `fixture`, each with its path in the fixture's
+// expected directory. Module files keep their position relative to the fixture.
+fn step_files(fixture: &Path, steps: Vec<Vec<(String, String)>>) -> Vec<(PathBuf, String)> {
+    let root = fixture.parent().unwrap_or(Path::new(""));
+    let mut files = Vec::new();
+    for (index, step) in steps.into_iter().enumerate() {
+        for (filename, text) in step {
+            let path = PathBuf::from(filename);
+            let stem = path.file_stem().and_then(|stem| stem.to_str()).unwrap_or("").to_owned();
+            let parent = path.parent().unwrap_or(Path::new(""));
+            let rel_dir = parent.strip_prefix(root).unwrap_or(Path::new(""));
+            files.push((rel_dir.join(output::apply_template(DEFAULT_TEMPLATE, &stem, index)), text));
+        }
+    }
+    files
+}
+
+// Every file under `dir`, by its path relative to `dir`.
+fn list(dir: &Path, rel: &Path, found: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in try!(fs::read_dir(dir.join(rel))) {
+        let entry = try!(entry);
+        let path = rel.join(entry.file_name());
+        if try!(entry.file_type()).is_dir() {
+            try!(list(dir, &path, found));
+        } else {
+            found.push(path);
+        }
+    }
+    Ok(())
+}
+
+// How the files in `expected` differ from `files`: each one missing, different
+// or left over from steps the fixture no longer has.
+fn check(expected: &Path, files: &[(PathBuf, String)]) -> Vec<String> {
+    let mut problems = Vec::new();
+    for &(ref rel, ref text) in files {
+        let path = expected.join(rel);
+        let mut old = String::new();
+        if File::open(&path).and_then(|mut f| f.read_to_string(&mut old)).is_err() {
+            problems.push(format!("{} is missing", path.display()));
+            continue;
+        }
+        if old != *text {
+            let name = path.to_string_lossy();
+            problems.push(format!("{} differs:\n{}", name, diff::unified_diff(&name, "output", &old, text)));
+        }
+    }
+    let mut found = Vec::new();
+    let _ = list(expected, Path::new(""), &mut found);
+    found.sort();
+    for rel in found {
+        if !files.iter().any(|&(ref file, _)| *file == rel) {
+            problems.push(format!("{} is not a step of the fixture", expected.join(rel).display()));
+        }
+    }
+    problems
+}
+
+// Replace the files in `expected` with `files`.
+fn bless(expected: &Path, files: &[(PathBuf, String)]) -> Result<(), String> {
+    if expected.exists() {
+        try!(fs::remove_dir_all(expected).map_err(|e| {
+            format!("could not remove {}: {}", expected.display(), e)
+        }));
+    }
+    for &(ref rel, ref text) in files {
+        let path = expected.join(rel);
+        try!(path.parent().map_or(Ok(()), |dir| fs::create_dir_all(dir))
+                 .and_then(|_| File::create(&path))
+                 .and_then(|mut file| file.write_all(text.as_bytes()))
+                 .map_err(|e| format!("could not write {}: {}", path.display(), e)));
+    }
+    Ok(())
+}
+
+// Run the golden tests of `backend` over the fixtures in `dir`, with `steps`
+// stepping through one fixture and returning the printed files of each step.
+// Exits with an error if any fixture's steps differ from its expected files,
+// or, with `--bless` on the command line, if they could not be written.
+pub fn main<F>(dir: &Path, backend: &str, steps: F)
+    where F: Fn(&Path) -> Result<Vec<Vec<(String, String)>>, String>
+{
+    let bless_all = env::args().skip(1).any(|arg| arg == "--bless");
+    let fixtures = match fixtures(dir) {
+        Ok(fixtures) => fixtures,
+        Err(msg) => {
+            let _ = writeln!(io::stderr(), "error: {}", msg);
+            process::exit(1);
+        }
+    };
+
+    let mut failed = 0;
+    for fixture in &fixtures {
+        let name = fixture.file_stem().and_then(|stem| stem.to_str()).unwrap_or("");
+        let expected = dir.join("expected").join(backend).join(name);
+        let result = steps(fixture).and_then(|printed| {
+            let files = step_files(fixture, printed);
+            if bless_all {
+                bless(&expected, &files).map(|_| Vec::new())
+            } else {
+                Ok(check(&expected, &files))
+            }
+        });
+        match result {
+            Ok(ref problems) if problems.is_empty() => {
+                println!("{} {} ... {}", backend, name, if bless_all { "blessed" } else { "ok" });
+            }
+            Ok(problems) => {
+                failed += 1;
+                println!("{} {} ... FAILED", backend, name);
+                for problem in problems {
+                    println!("  {}", problem.trim_right().replace('\n', "\n  "));
+                }
+            }
+            Err(msg) => {
+                failed += 1;
+                println!("{} {} ... FAILED\n  {}", backend, name, msg);
+            }
+        }
+    }
+
+    println!("\n{} fixture(s), {} failed", fixtures.len(), failed);
+    if failed > 0 {
+        if !bless_all {
+            println!("run with `--bless` to accept the new output as expected");
+        }
+        process::exit(1);
+    }
+}
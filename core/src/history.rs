@@ -0,0 +1,168 @@
+// The printed files of every step of a session, kept so that any step can be
+// had again without keeping its AST. Each step is stored as what changed in
+// each file since the step before: one expansion step rewrites a few places,
+// so a long run over a large crate does not hold a copy of it per step.
+
+use diff::{self, Edit};
+
+// A run of lines of a file that a step replaced: `removed` lines from line
+// `at` of the file in the step before, and the lines put in their place.
+struct Hunk {
+    at: usize,
+    removed: usize,
+    added: String,
+}
+
+// A file of a step, as the hunks that turn the same file in the step before
+// into it.
+struct FileDelta {
+    name: String,
+    hunks: Vec<Hunk>,
+}
+
+// The lines of `text`, each with its line ending.
+fn lines(text: &str) -> Vec<&str> {
+    let mut lines = Vec::new();
+    let mut start = 0;
+    for (end, _) in text.match_indices('\n') {
+        lines.push(&text[start..end + 1]);
+        start = end + 1;
+    }
+    if start < text.len() {
+        lines.push(&text[start..]);
+    }
+    lines
+}
+
+impl FileDelta {
+    fn new(name: &str, old: &str, new: &str) -> FileDelta {
+        let (old, new) = (lines(old), lines(new));
+        let mut hunks: Vec<Hunk> = Vec::new();
+        let (mut i, mut j) = (0, 0);
+        // Whether the last edit changed a line, so that this one adds to its hunk.
+        let mut changing = false;
+        for edit in diff::edit_script(&old, &new) {
+            if edit == Edit::Keep {
+                changing = false;
+                i += 1;
+                j += 1;
+                continue;
+            }
+            if !changing {
+                hunks.push(Hunk { at: i, removed: 0, added: String::new() });
+                changing = true;
+            }
+            let hunk = hunks.last_mut().unwrap();
+            if edit == Edit::Delete {
+                hunk.removed += 1;
+                i += 1;
+            } else {
+                hunk.added.push_str(new[j]);
+                j += 1;
+            }
+        }
+        FileDelta { name: name.to_owned(), hunks: hunks }
+    }
+
+    // The file, given the same file in the step before.
+    fn apply(&self, old: &str) -> String {
+        let old = lines(old);
+        let mut text = String::new();
+        let mut next = 0;
+        for hunk in &self.hunks {
+            text.push_str(&old[next..hunk.at].concat());
+            text.push_str(&hunk.added);
+            next = hunk.at + hunk.removed;
+        }
+        text.push_str(&old[next..].concat());
+        text
+    }
+}
+
+// The text of the file `name` among `files`, or nothing if it is not one.
+fn text_of<'f>(files: &'f [(String, String)], name: &str) -> &'f str {
+    files.iter().find(|&&(ref file, _)| file == name).map_or("", |&(_, ref text)| &text[..])
+}
+
+#[derive(Default)]
+pub struct History {
+    steps: Vec<Vec<FileDelta>>,
+    // The files of the latest step, which the next one is compared with.
+    latest: Vec<(String, String)>,
+}
+
+impl History {
+    // Record the files of the next step.
+    pub fn push(&mut self, files: &[(String, String)]) {
+        let step = files.iter().map(|&(ref name, ref text)| {
+            FileDelta::new(name, text_of(&self.latest, name), text)
+        }).collect();
+        self.steps.push(step);
+        self.latest = files.to_vec();
+    }
+
+    // The number of steps recorded.
+    pub fn len(&self) -> usize {
+        self.steps.len()
+    }
+
+    // The files of step `index`, one (filename, source) pair per module file,
+    // rebuilt from the first step on.
+    pub fn get(&self, index: usize) -> Option<Vec<(String, String)>> {
+        if index >= self.steps.len() {
+            return None;
+        }
+        let mut files: Vec<(String, String)> = Vec::new();
+        for step in &self.steps[..index + 1] {
+            let next = step.iter().map(|delta| {
+                (delta.name.clone(), delta.apply(text_of(&files, &delta.name)))
+            }).collect();
+            files = next;
+        }
+        Some(files)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::History;
+
+    fn files(list: &[(&str, &str)]) -> Vec<(String, String)> {
+        list.iter().map(|&(name, text)| (name.to_owned(), text.to_owned())).collect()
+    }
+
+    #[test]
+    fn every_step_is_rebuilt_as_it_was_pushed() {
+        let steps = vec![
+            files(&[("a.rs", "fn main() {\n    foo!();\n}\n"), ("b.rs", "mod c;\n")]),
+            files(&[("a.rs", "fn main() {\n    let x = 1;\n    bar!(x);\n}\n"), ("b.rs", "mod c;\n")]),
+            files(&[("a.rs", "fn main() {\n    let x = 1;\n    x\n}"), ("c.rs", "struct S;\n")]),
+            files(&[("a.rs", ""), ("c.rs", "struct S;\n")]),
+        ];
+        let mut history = History::default();
+        for step in &steps {
+            history.push(step);
+        }
+        assert_eq!(history.len(), steps.len());
+        for (index, step) in steps.iter().enumerate() {
+            assert_eq!(history.get(index).as_ref(), Some(step));
+        }
+        assert!(history.get(steps.len()).is_none());
+    }
+
+    #[test]
+    fn a_step_keeps_only_the_lines_it_changed() {
+        let old: Vec<String> = (0..1000).map(|i| format!("line {}\n", i)).collect();
+        let mut new = old.clone();
+        new[10] = "changed\n".to_owned();
+        new.insert(500, "added\n".to_owned());
+        let mut history = History::default();
+        history.push(&files(&[("a.rs", &old.concat())]));
+        history.push(&files(&[("a.rs", &new.concat())]));
+        let hunks = &history.steps[1][0].hunks;
+        assert_eq!(hunks.len(), 2);
+        assert_eq!((hunks[0].at, hunks[0].removed, &hunks[0].added[..]), (10, 1, "changed\n"));
+        assert_eq!((hunks[1].at, hunks[1].removed, &hunks[1].added[..]), (500, 0, "added\n"));
+        assert_eq!(history.get(1).unwrap()[0].1, new.concat());
+    }
+}
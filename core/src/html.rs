@@ -0,0 +1,219 @@
+// Standalone HTML viewer for an expansion trace: a slider over the steps, with
+// the input file next to the printed step. Code that came from a macro is
+// shaded by whether it was written in the macro's body or in the invocation's
+// arguments, and hovering it highlights the invocation in the input file that
+// produced it.
+
+use rustc_serialize::json;
+
+use markers::{self, OPEN, SEP, CLOSE};
+
+use std::collections::HashMap;
+
+// The user-written invocation that a region of expanded code came from.
+pub struct Origin {
+    // The macros between the invocation and the code, outermost first.
+    pub chain: Vec<String>,
+    // Where the invocation is in the input file, as `line:col`.
+    pub location: String,
+    // Character offsets of the invocation in the input file.
+    pub lo: usize,
+    pub hi: usize,
+}
+
+encodable!(Origin { chain, location, lo, hi });
+
+// Every origin seen so far, so that an invocation keeps the same index in
+// every step.
+#[derive(Default)]
+pub struct Origins {
+    list: Vec<Origin>,
+    ids: HashMap<(usize, usize, Vec<String>), usize>,
+}
+
+impl Origins {
+    pub fn id(&mut self, origin: Origin) -> usize {
+        let key = (origin.lo, origin.hi, origin.chain.clone());
+        if let Some(&id) = self.ids.get(&key) {
+            return id;
+        }
+        self.list.push(origin);
+        self.ids.insert(key, self.list.len() - 1);
+        self.list.len() - 1
+    }
+}
+
+// A printed step, still containing the annotation markers, with each node
+// marked by the index of its origin and the side of the invocation it was
+// written on.
+pub struct Step {
+    pub heading: String,
+    pub text: String,
+}
+
+fn escape(out: &mut String, c: char) {
+    match c {
+        '<' => out.push_str("&lt;"),
+        '>' => out.push_str("&gt;"),
+        '&' => out.push_str("&amp;"),
+        '"' => out.push_str("&quot;"),
+        _ => out.push(c),
+    }
+}
+
+// Turn the markers in a printed step into nested `<span>`s, classed by side,
+// escaping the rest.
+fn markup(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut depth = 0;
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            OPEN => {
+                let label: String = chars.by_ref().take_while(|&c| c != SEP).collect();
+                let (id, side) = markers::parse_label(&label);
+                out.push_str(&format!("<span class=\"exp {}\" data-origin=\"{}\">", side.name(), id));
+                depth += 1;
+            }
+            CLOSE if depth > 0 => {
+                out.push_str("</span>");
+                depth -= 1;
+            }
+            CLOSE => {}
+            _ => escape(&mut out, c),
+        }
+    }
+    for _ in 0..depth {
+        out.push_str("</span>");
+    }
+    out
+}
+
+const TEMPLATE: &'static str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>{{title}}</title>
+<style>
+body { font-family: sans-serif; margin: 0; display: flex; flex-direction: column; height: 100vh; }
+header { padding: 8px 12px; border-bottom: 1px solid #ccc; display: flex; gap: 12px; align-items: center; }
+header input { flex: 1; }
+#heading { font-family: monospace; }
+main { flex: 1; display: flex; min-height: 0; }
+section { flex: 1; display: flex; flex-direction: column; min-width: 0; }
+section h2 { font-size: 14px; margin: 0; padding: 4px 12px; background: #f4f4f4; }
+pre { flex: 1; margin: 0; padding: 8px 12px; overflow: auto; border-right: 1px solid #ccc; }
+.exp.callee { background: #eef6ff; }
+.exp.call_site { background: #eefbe8; }
+.exp.hover { background: #ffe9a8; }
+mark { background: #ffd54f; }
+</style>
+</head>
+<body>
+<header>
+<label for="step">Step <span id="index"></span></label>
+<input id="step" type="range" min="0" value="0">
+<span id="heading"></span>
+<span class="exp callee">macro body</span>
+<span class="exp call_site">arguments</span>
+</header>
+<main>
+<section><h2>Source: {{title}}</h2><pre id="source"></pre></section>
+<section><h2>Expanded <span id="via"></span></h2><pre id="expanded"></pre></section>
+</main>
+<script>
+var source = {{source}};
+var origins = {{origins}};
+var steps = {{steps}};
+var headings = {{headings}};
+
+function escape(text) {
+    return text.replace(/&/g, "&amp;").replace(/</g, "&lt;").replace(/>/g, "&gt;");
+}
+
+function highlight(origin) {
+    var pane = document.getElementById("source");
+    var via = document.getElementById("via");
+    if (origin === null) {
+        pane.innerHTML = escape(source);
+        via.textContent = "";
+        return;
+    }
+    pane.innerHTML = escape(source.slice(0, origin.lo)) +
+                     "<mark>" + escape(source.slice(origin.lo, origin.hi)) + "</mark>" +
+                     escape(source.slice(origin.hi));
+    pane.querySelector("mark").scrollIntoView({ block: "nearest" });
+    via.textContent = "from " + origin.chain.join(" → ") + " at " + origin.location;
+}
+
+function show(index) {
+    document.getElementById("index").textContent = index + " / " + (steps.length - 1);
+    document.getElementById("heading").textContent = headings[index];
+    var pane = document.getElementById("expanded");
+    pane.innerHTML = steps[index];
+    var regions = pane.querySelectorAll(".exp");
+    for (var i = 0; i < regions.length; i++) {
+        regions[i].addEventListener("mouseover", function (event) {
+            event.stopPropagation();
+            var current = pane.querySelector(".exp.hover");
+            if (current) current.classList.remove("hover");
+            this.classList.add("hover");
+            highlight(origins[this.getAttribute("data-origin")]);
+        });
+    }
+    pane.onmouseleave = function () {
+        var current = pane.querySelector(".exp.hover");
+        if (current) current.classList.remove("hover");
+        highlight(null);
+    };
+}
+
+var slider = document.getElementById("step");
+slider.max = steps.length - 1;
+slider.addEventListener("input", function () { show(parseInt(slider.value, 10)); });
+highlight(null);
+show(0);
+</script>
+</body>
+</html>
+"#;
+
+// The viewer page for the steps of `source`. JSON is also valid JavaScript, so
+// the data is embedded with the JSON encoder; `</` is escaped so that no
+// string can end the script element.
+pub fn render(title: &str, source: &str, origins: &Origins, steps: &[Step]) -> String {
+    let embed = |json: String| json.replace("</", "<\\/");
+    let marked: Vec<String> = steps.iter().map(|step| markup(&step.text)).collect();
+    let headings: Vec<&str> = steps.iter().map(|step| &step.heading[..]).collect();
+    let mut title_html = String::new();
+    for c in title.chars() {
+        escape(&mut title_html, c);
+    }
+    fill(TEMPLATE, &[("title", title_html),
+                     ("source", embed(json::encode(&source).unwrap())),
+                     ("origins", embed(json::encode(&origins.list).unwrap())),
+                     ("steps", embed(json::encode(&marked).unwrap())),
+                     ("headings", embed(json::encode(&headings).unwrap()))])
+}
+
+// Replace each `{{key}}` in `template` with its value. Values are not scanned
+// again, so they may contain anything.
+fn fill(template: &str, values: &[(&str, String)]) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let end = match rest[start..].find("}}") {
+            Some(len) => start + len + 2,
+            None => break,
+        };
+        let key = &rest[start + 2..end - 2];
+        out.push_str(&rest[..start]);
+        match values.iter().find(|&&(name, _)| name == key) {
+            Some(&(_, ref value)) => out.push_str(value),
+            None => out.push_str(&rest[start..end]),
+        }
+        rest = &rest[end..];
+    }
+    out.push_str(rest);
+    out
+}
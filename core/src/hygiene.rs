@@ -0,0 +1,149 @@
+// Hygiene in the steps printed by the backends over a `syntax` crate. The
+// printer writes an identifier by its name alone, so a variable a macro
+// introduced prints the same as the user's of the same name, though the
+// expander keeps them apart by their syntax contexts.
+//
+// As with `span_fixing!`, this is a macro expanded in each backend, where
+// `syntax` names the backend's crate. `hygiene!()` defines the `hygiene`
+// module, with the `MarksAnn` annotation for `--hygiene marks` and the `rename`
+// fold for `--hygiene rename`.
+#[macro_export]
+macro_rules! hygiene {
+    () => {
+        mod hygiene {
+            use syntax::ast::{self, EMPTY_CTXT};
+            use syntax::fold::{self, Folder};
+            use syntax::parse::token;
+            use syntax::print::pp;
+            use syntax::print::pprust::{AnnNode, PpAnn, State};
+            use syntax::ptr::P;
+
+            use std::collections::{HashMap, HashSet};
+            use std::io;
+
+            // Writes the syntax context after every identifier that has one, as in `x#3`,
+            // around the annotations of `inner`.
+            pub struct MarksAnn<'a> {
+                inner: &'a PpAnn,
+            }
+
+            impl<'a> MarksAnn<'a> {
+                pub fn new(inner: &'a PpAnn) -> MarksAnn<'a> {
+                    MarksAnn { inner: inner }
+                }
+            }
+
+            impl<'a> PpAnn for MarksAnn<'a> {
+                fn pre(&self, state: &mut State, node: AnnNode) -> io::Result<()> {
+                    self.inner.pre(state, node)
+                }
+
+                fn post(&self, state: &mut State, node: AnnNode) -> io::Result<()> {
+                    if let AnnNode::NodeIdent(ident) = node {
+                        if ident.ctxt != EMPTY_CTXT {
+                            try!(pp::word(&mut state.s, &format!("#{}", ident.ctxt.0)));
+                        }
+                    }
+                    self.inner.post(state, node)
+                }
+            }
+
+            // `krate` with every variable bound with a syntax context renamed to
+            // `name_ctxt`, as are the identifiers that refer to it, so that the step keeps
+            // its meaning when compiled on its own.
+            pub fn rename(krate: ast::Crate) -> ast::Crate {
+                let mut finder = BindingFinder { bindings: HashSet::new(), names: HashSet::new() };
+                let krate = finder.fold_crate(krate);
+                BindingRenamer { renamed: finder.fresh_names() }.fold_crate(krate)
+            }
+
+            // Gathers the identifiers that patterns bind with a syntax context, and
+            // every name in the crate, which a new name must not be.
+            struct BindingFinder {
+                bindings: HashSet<ast::Ident>,
+                names: HashSet<ast::Name>,
+            }
+
+            impl BindingFinder {
+                // A new name for each binding, `name_ctxt`, or `name_ctxt_N` with the
+                // first `N` that makes it unlike every other name in the crate.
+                fn fresh_names(mut self) -> HashMap<ast::Ident, ast::Ident> {
+                    let mut bindings: Vec<ast::Ident> = self.bindings.iter().cloned().collect();
+                    bindings.sort_by_key(|ident| (ident.name.as_str().to_string(), ident.ctxt.0));
+                    bindings.into_iter().map(|ident| {
+                        let base = format!("{}_{}", ident.name, ident.ctxt.0);
+                        let mut name = token::intern(&base);
+                        let mut n = 0;
+                        while self.names.contains(&name) {
+                            n += 1;
+                            name = token::intern(&format!("{}_{}", base, n));
+                        }
+                        self.names.insert(name);
+                        (ident, ast::Ident::new(name, EMPTY_CTXT))
+                    }).collect()
+                }
+            }
+
+            impl Folder for BindingFinder {
+                fn fold_ident(&mut self, ident: ast::Ident) -> ast::Ident {
+                    self.names.insert(ident.name);
+                    ident
+                }
+
+                fn fold_pat(&mut self, pat: P<ast::Pat>) -> P<ast::Pat> {
+                    if let ast::PatKind::Ident(_, ref ident, _) = pat.node {
+                        if ident.node.ctxt != EMPTY_CTXT {
+                            self.bindings.insert(ident.node);
+                        }
+                    }
+                    fold::noop_fold_pat(pat, self)
+                }
+
+                fn fold_mac(&mut self, mac: ast::Mac) -> ast::Mac {
+                    mac
+                }
+            }
+
+            struct BindingRenamer {
+                renamed: HashMap<ast::Ident, ast::Ident>,
+            }
+
+            impl BindingRenamer {
+                fn rename(&self, ident: ast::Ident) -> ast::Ident {
+                    self.renamed.get(&ident).cloned().unwrap_or(ident)
+                }
+            }
+
+            // Only the identifiers that bind a variable and the paths that can refer to
+            // one are renamed: field and method names, and the segments of longer paths,
+            // keep theirs even when they share a binding's name and context.
+            impl Folder for BindingRenamer {
+                fn fold_pat(&mut self, pat: P<ast::Pat>) -> P<ast::Pat> {
+                    fold::noop_fold_pat(pat, self).map(|mut pat| {
+                        if let ast::PatKind::Ident(_, ref mut ident, _) = pat.node {
+                            ident.node = self.rename(ident.node);
+                        }
+                        pat
+                    })
+                }
+
+                fn fold_expr(&mut self, expr: P<ast::Expr>) -> P<ast::Expr> {
+                    expr.map(|expr| {
+                        let mut expr = fold::noop_fold_expr(expr, self);
+                        if let ast::ExprKind::Path(None, ref mut path) = expr.node {
+                            if !path.global && path.segments.len() == 1 {
+                                let renamed = self.rename(path.segments[0].identifier);
+                                path.segments[0].identifier = renamed;
+                            }
+                        }
+                        expr
+                    })
+                }
+
+                fn fold_mac(&mut self, mac: ast::Mac) -> ast::Mac {
+                    mac
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,88 @@
+// The parts of the macro expander that do not depend on a `syntax` crate: the
+// command line, the stepping session and engine behind the `Backend` trait, and
+// the output formats. The `expander` (syntex), `rust-expander` (libsyntax) and
+// `stable-expander` (syn) crates are frontends over it.
+
+// The crate is written for the 2015 edition and the standard library of its
+// day: `try!` rather than `?`, no `matches!` or `strip_prefix`, and the idioms
+// that went with them. Newer lints against those are not followed.
+#![allow(deprecated)]
+#![allow(clippy::large_enum_variant,
+         clippy::len_without_is_empty,
+         clippy::manual_strip,
+         clippy::match_like_matches_macro,
+         clippy::mem_replace_with_default,
+         clippy::needless_borrowed_reference,
+         clippy::redundant_closure,
+         clippy::redundant_field_names,
+         clippy::redundant_static_lifetimes,
+         clippy::result_unit_err,
+         clippy::unnecessary_map_or,
+         clippy::unwrap_or_default,
+         clippy::while_let_on_iterator)]
+
+extern crate getopts;
+extern crate rustc_serialize;
+
+#[macro_use]
+mod annotate;
+#[macro_use]
+mod attempt;
+#[macro_use]
+mod hygiene;
+#[macro_use]
+mod serialize;
+#[macro_use]
+mod span_fix;
+
+pub mod backtrace;
+pub mod cli;
+pub mod diff;
+pub mod dot;
+pub mod engine;
+pub mod events;
+pub mod golden;
+pub mod history;
+pub mod html;
+pub mod markers;
+pub mod outcome;
+pub mod output;
+pub mod session;
+pub mod term;
+pub mod whence;
+
+use cli::{Command, Options};
+
+use std::env;
+use std::io::{self, Write};
+use std::process;
+
+// Parse the command line and run the chosen command, with `run` expanding the
+// crate through a backend. Exits on errors.
+pub fn main(run: fn(&Options) -> Result<(), String>) {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let options = match cli::parse_args(&args) {
+        Ok(cli::Action::Run(options)) => options,
+        Ok(cli::Action::Help) => {
+            print!("{}", cli::usage());
+            return;
+        }
+        Err(msg) => {
+            let _ = writeln!(io::stderr(), "error: {}\n{}", msg, cli::short_usage());
+            process::exit(2);
+        }
+    };
+
+    let result = if options.command == Command::Clean {
+        let dir = options.output_dir();
+        output::clean(&dir, &options.filename, options.force).map(|removed| {
+            println!("Removed {} file(s) from {}", removed, dir.display());
+        })
+    } else {
+        run(&options)
+    };
+    if let Err(msg) = result {
+        let _ = writeln!(io::stderr(), "error: {}", msg);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,99 @@
+// Markers that a backend's pretty-printer annotation writes around code that
+// came from a macro expansion, for the HTML and JSON traces.
+
+use std::collections::HashMap;
+
+// Markers written around a marked node: `OPEN`, its label, `SEP`, the node,
+// then `CLOSE`. They are in the private use area, so never clash with the
+// printed source, and are written as zero-width words, so the printer lays out
+// the code exactly as it would without them.
+pub const OPEN: char = '\u{E000}';
+pub const SEP: char = '\u{E001}';
+pub const CLOSE: char = '\u{E002}';
+
+// Which side of the invocation that produced it a piece of expanded code was
+// written on: in the body of the macro, or in the arguments of the invocation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Side {
+    Callee,
+    CallSite,
+}
+
+impl Side {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Side::Callee => "callee",
+            Side::CallSite => "call_site",
+        }
+    }
+}
+
+// The marker before a node marked with `id`, written on `side`. The label is
+// the id in decimal, `:` and the side's name.
+pub fn open(id: usize, side: Side) -> String {
+    format!("{}{}:{}{}", OPEN, id, side.name(), SEP)
+}
+
+// The id and side in a marker's label.
+pub fn parse_label(label: &str) -> (usize, Side) {
+    let mut parts = label.splitn(2, ':');
+    let id = parts.next().and_then(|id| id.parse().ok()).unwrap_or(0);
+    let side = match parts.next() {
+        Some("call_site") => Side::CallSite,
+        _ => Side::Callee,
+    };
+    (id, side)
+}
+
+// A stretch of text inside the same innermost marker.
+pub struct Segment {
+    pub id: usize,
+    pub side: Side,
+    pub lo: usize,
+    pub hi: usize,
+}
+
+// Remove the markers from printed text, returning the text, the byte range
+// from the first start to the last end of each id, and the segments of marked
+// text in order.
+pub fn strip_markers(text: &str) -> (String, HashMap<usize, (usize, usize)>, Vec<Segment>) {
+    let mut out = String::with_capacity(text.len());
+    let mut ranges: HashMap<usize, (usize, usize)> = HashMap::new();
+    let mut segments: Vec<Segment> = Vec::new();
+    let mut open = Vec::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            OPEN => {
+                let label: String = chars.by_ref().take_while(|&c| c != SEP).collect();
+                let (id, side) = parse_label(&label);
+                let start = out.len();
+                ranges.entry(id).or_insert((start, start));
+                open.push((id, side));
+            }
+            CLOSE => {
+                if let Some((id, _)) = open.pop() {
+                    if let Some(range) = ranges.get_mut(&id) {
+                        range.1 = out.len();
+                    }
+                }
+            }
+            _ => {
+                let start = out.len();
+                out.push(c);
+                let (id, side) = match open.last() {
+                    Some(&mark) => mark,
+                    None => continue,
+                };
+                if let Some(last) = segments.last_mut() {
+                    if last.id == id && last.side == side && last.hi == start {
+                        last.hi = out.len();
+                        continue;
+                    }
+                }
+                segments.push(Segment { id: id, side: side, lo: start, hi: out.len() });
+            }
+        }
+    }
+    (out, ranges, segments)
+}
@@ -0,0 +1,112 @@
+// What an invocation written in the input file expands to in a printed step,
+// for `outcome`: only its code, with every expansion nested in it resolved.
+
+use rustc_serialize::json;
+
+use events::{Event, Position, SpanInfo};
+use markers;
+
+use std::cmp;
+
+// The invocation asked about: the one written at a 1-based line and column of
+// the input file, or the `n`th expanded in it, counting from 0 in source order.
+pub enum Target {
+    Position(usize, usize),
+    Index(usize),
+}
+
+pub struct Outcome {
+    pub step: usize,
+    // The id of the invocation's event.
+    pub event: usize,
+    // The macro, with its `!`.
+    pub name: String,
+    pub call_site: SpanInfo,
+    // The code it expands to, empty when it expands to nothing.
+    pub code: String,
+}
+
+encodable!(Outcome { step, event, name, call_site, code });
+
+fn location(span: &SpanInfo) -> String {
+    format!("{}:{}:{}", span.file, span.lo.line, span.lo.col)
+}
+
+impl Outcome {
+    // The code, after a comment naming the invocation.
+    pub fn report(&self) -> String {
+        let heading = format!("// {} at {} in step {}", self.name, location(&self.call_site), self.step);
+        if self.code.is_empty() {
+            format!("{}: expands to nothing", heading)
+        } else {
+            format!("{}\n{}", heading, self.code)
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        format!("{}\n", json::as_pretty_json(self))
+    }
+}
+
+// The expansion of the invocation in `file` that `target` picks.
+fn invocation<'e>(file: &str, events: &'e [Event], target: &Target) -> Result<&'e Event, String> {
+    let mut roots: Vec<&Event> = events.iter()
+                                       .filter(|event| event.parent.is_none() && event.call_site.file == file)
+                                       .collect();
+    roots.sort_by(|a, b| a.call_site.lo.cmp(&b.call_site.lo));
+    match *target {
+        Target::Position(line, col) => {
+            let at = Position { line: line, col: col };
+            roots.into_iter()
+                 .find(|event| event.call_site.lo <= at && at < event.call_site.hi)
+                 .ok_or(format!("no invocation expanded so far is written at {}:{}:{}", file, line, col))
+        }
+        Target::Index(n) => {
+            let count = roots.len();
+            roots.into_iter().nth(n).ok_or(format!("{} invocations in {} were expanded so far, \
+                                                    counting from 0; there is no invocation {}",
+                                                   count, file, n))
+        }
+    }
+}
+
+// `text[lo..hi]`, with the indentation of the line it starts on taken off
+// every line after the first.
+fn dedent(text: &str, lo: usize, hi: usize) -> String {
+    let start = text[..lo].rfind('\n').map_or(0, |i| i + 1);
+    let indent = text[start..lo].chars().take_while(|&c| c == ' ').count();
+    let mut lines = text[lo..hi].split('\n');
+    let mut code = lines.next().unwrap_or("").to_owned();
+    for line in lines {
+        let spaces = line.chars().take(indent).take_while(|&c| c == ' ').count();
+        code.push('\n');
+        code.push_str(&line[spaces..]);
+    }
+    code
+}
+
+// What the invocation in `file` that `target` picks expands to in `marked`,
+// step `step` as a backend's `print_outputs` prints it, given every expansion
+// so far. The code runs from the first to the last that any expansion with the
+// invocation as its root produced, which takes in the arguments it passed on.
+pub fn outcome(file: &str,
+               step: usize,
+               marked: &str,
+               events: &[Event],
+               target: Target) -> Result<Outcome, String> {
+    let event = try!(invocation(file, events, &target));
+    let (text, ranges, _) = markers::strip_markers(marked);
+    let mut range: Option<(usize, usize)> = None;
+    for (&id, &(lo, hi)) in &ranges {
+        if events[id].root == event.id {
+            range = Some(range.map_or((lo, hi), |(start, end)| (cmp::min(start, lo), cmp::max(end, hi))));
+        }
+    }
+    Ok(Outcome {
+        step: step,
+        event: event.id,
+        name: event.name.clone(),
+        call_site: event.call_site.clone(),
+        code: range.map_or(String::new(), |(lo, hi)| dedent(&text, lo, hi)),
+    })
+}
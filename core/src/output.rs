@@ -0,0 +1,180 @@
+// Management of the directory that step files are written to.
+//
+// Every run records the files it wrote in a `.macro-expander.json` manifest
+// next to them. A later run of the same input into the same directory first
+// removes those files, and refuses to overwrite anything else unless forced; so
+// do a run and a `clean` of another input, before removing anything.
+
+use rustc_serialize::json;
+
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+pub const MANIFEST_NAME: &'static str = ".macro-expander.json";
+pub const DEFAULT_TEMPLATE: &'static str = "{stem}Output{step}.rs";
+
+pub struct Manifest {
+    pub input: String,
+    pub steps: Vec<StepEntry>,
+}
+
+encodable!(Manifest { input, steps });
+decodable!(Manifest { input, steps });
+
+pub struct StepEntry {
+    pub step: usize,
+    // Paths relative to the output directory.
+    pub files: Vec<String>,
+    // The macro invocations expanded to produce this step.
+    pub expanded: Vec<String>,
+}
+
+encodable!(StepEntry { step, files, expanded });
+decodable!(StepEntry { step, files, expanded });
+
+impl Manifest {
+    fn load(dir: &Path) -> Result<Option<Manifest>, String> {
+        let path = dir.join(MANIFEST_NAME);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let mut text = String::new();
+        try!(File::open(&path).and_then(|mut f| f.read_to_string(&mut text))
+             .map_err(|e| format!("could not read {}: {}", path.display(), e)));
+        json::decode(&text).map(Some)
+                           .map_err(|e| format!("malformed {}: {}", path.display(), e))
+    }
+
+    fn save(&self, dir: &Path) -> Result<(), String> {
+        let path = dir.join(MANIFEST_NAME);
+        let text = format!("{}\n", json::as_pretty_json(self));
+        File::create(&path).and_then(|mut f| f.write_all(text.as_bytes()))
+                           .map_err(|e| format!("could not write {}: {}", path.display(), e))
+    }
+
+    fn files(&self) -> Vec<String> {
+        self.steps.iter().flat_map(|step| step.files.iter().cloned()).collect()
+    }
+}
+
+// Substitute `{stem}` and `{step}` in a step file name template.
+pub fn apply_template(template: &str, stem: &str, step: usize) -> String {
+    template.replace("{stem}", stem).replace("{step}", &step.to_string())
+}
+
+pub fn check_template(template: &str) -> Result<(), String> {
+    if !template.contains("{step}") {
+        return Err(format!("name template '{}' must contain {{step}}", template));
+    }
+    if template.contains('/') || template.contains('\\') {
+        return Err(format!("name template '{}' must be a file name, not a path", template));
+    }
+    Ok(())
+}
+
+pub struct OutputDir {
+    dir: PathBuf,
+    template: String,
+    force: bool,
+    manifest: Manifest,
+}
+
+impl OutputDir {
+    pub fn new(dir: PathBuf,
+               template: String,
+               force: bool,
+               input: &str) -> Result<OutputDir, String> {
+        try!(fs::create_dir_all(&dir)
+             .map_err(|e| format!("could not create {}: {}", dir.display(), e)));
+        // The files of an earlier run are only removed when it was of the same
+        // input, so that expanding two crates into one directory by mistake
+        // does not lose the first one's steps.
+        if let Some(manifest) = try!(Manifest::load(&dir)) {
+            try!(check_input(&dir, &manifest, input, force));
+            try!(remove(&dir, &manifest));
+        }
+        Ok(OutputDir {
+            dir: dir,
+            template: template,
+            force: force,
+            manifest: Manifest { input: input.to_owned(), steps: Vec::new() },
+        })
+    }
+
+    // Path of the step file for `stem`, inside the subdirectory `rel_dir`.
+    pub fn path_for(&self, rel_dir: &Path, stem: &str, step: usize) -> PathBuf {
+        self.dir.join(rel_dir).join(apply_template(&self.template, stem, step))
+    }
+
+    // Path of a file written once for the whole run, such as the HTML viewer.
+    pub fn file_path(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+
+    // Check that `path` may be written, and create its parent directory.
+    pub fn claim(&self, path: &Path) -> Result<(), String> {
+        if path.exists() && !self.force {
+            return Err(format!("refusing to overwrite {}, which was not written by a previous \
+                                run (use --force to overwrite it)", path.display()));
+        }
+        match path.parent() {
+            Some(parent) => fs::create_dir_all(parent)
+                            .map_err(|e| format!("could not create {}: {}", parent.display(), e)),
+            None => Ok(()),
+        }
+    }
+
+    // Record the files written for a step and rewrite the manifest.
+    pub fn record(&mut self,
+                  step: usize,
+                  files: &[PathBuf],
+                  expanded: Vec<String>) -> Result<(), String> {
+        let files = files.iter().map(|path| self.relative(path)).collect();
+        self.manifest.steps.push(StepEntry { step: step, files: files, expanded: expanded });
+        self.manifest.save(&self.dir)
+    }
+
+    fn relative(&self, path: &Path) -> String {
+        path.strip_prefix(&self.dir).unwrap_or(path).to_string_lossy().into_owned()
+    }
+}
+
+// Remove the step files and manifest of an earlier run of `input` in `dir`,
+// returning the number of files removed.
+pub fn clean(dir: &Path, input: &str, force: bool) -> Result<usize, String> {
+    match try!(Manifest::load(dir)) {
+        Some(manifest) => {
+            try!(check_input(dir, &manifest, input, force));
+            remove(dir, &manifest)
+        }
+        None => Err(format!("no {} found", dir.join(MANIFEST_NAME).display())),
+    }
+}
+
+// Refuse to remove the steps `manifest` lists when they are of another input
+// than `input`, unless forced.
+fn check_input(dir: &Path, manifest: &Manifest, input: &str, force: bool) -> Result<(), String> {
+    if manifest.input != input && !force {
+        return Err(format!("{} lists the steps of {}, not {} (use --force to replace them)",
+                           dir.join(MANIFEST_NAME).display(), manifest.input, input));
+    }
+    Ok(())
+}
+
+// Remove the files listed in `manifest`, and the manifest itself, from `dir`.
+fn remove(dir: &Path, manifest: &Manifest) -> Result<usize, String> {
+    let mut removed = 0;
+    for file in manifest.files() {
+        let path = dir.join(&file);
+        if path.exists() {
+            try!(fs::remove_file(&path)
+                 .map_err(|e| format!("could not remove {}: {}", path.display(), e)));
+            removed += 1;
+        }
+    }
+    let path = dir.join(MANIFEST_NAME);
+    try!(fs::remove_file(&path)
+         .map_err(|e| format!("could not remove {}: {}", path.display(), e)));
+    Ok(removed)
+}
@@ -0,0 +1,43 @@
+// JSON encoding and decoding of the records core writes, field by field in the
+// order given, as `#[derive(RustcEncodable, RustcDecodable)]` would write them.
+// The derives are a compiler plugin that current compilers no longer have, and
+// `stable-expander` has to build on a stable one.
+
+// `encodable!(Name { field, ... })` implements `Encodable` for the struct `Name`.
+macro_rules! encodable {
+    ($name:ident { $($field:ident),* }) => {
+        impl ::rustc_serialize::Encodable for $name {
+            fn encode<S: ::rustc_serialize::Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+                let len = [$(stringify!($field)),*].len();
+                s.emit_struct(stringify!($name), len, |s| {
+                    encodable!(@fields self, s, 0, $($field),*);
+                    Ok(())
+                })
+            }
+        }
+    };
+    (@fields $this:ident, $s:ident, $index:expr, $field:ident $(, $rest:ident)*) => {
+        try!($s.emit_struct_field(stringify!($field), $index, |s| {
+            ::rustc_serialize::Encodable::encode(&$this.$field, s)
+        }));
+        encodable!(@fields $this, $s, $index + 1, $($rest),*);
+    };
+    (@fields $this:ident, $s:ident, $index:expr,) => {};
+}
+
+// `decodable!(Name { field, ... })` implements `Decodable` for the struct `Name`.
+macro_rules! decodable {
+    ($name:ident { $($field:ident),* }) => {
+        impl ::rustc_serialize::Decodable for $name {
+            fn decode<D: ::rustc_serialize::Decoder>(d: &mut D) -> Result<$name, D::Error> {
+                let len = [$(stringify!($field)),*].len();
+                d.read_struct(stringify!($name), len, |d| {
+                    Ok($name {
+                        $($field: try!(d.read_struct_field(stringify!($field), 0,
+                                                           ::rustc_serialize::Decodable::decode)),)*
+                    })
+                })
+            }
+        }
+    };
+}
@@ -0,0 +1,293 @@
+// Stepping through the expansion of a crate as an iterator, for embedding the
+// stepper in other tools. Each backend wraps `Steps` in an `ExpansionSession`
+// that owns everything its `syntax` crate needs; nothing is written to disk.
+
+use backtrace::{self, Frame};
+use cli::{Hygiene, MacroFilter, Order};
+use engine::{Backend, Invocation};
+use events::Diagnostic;
+use history::History;
+use outcome::{self, Outcome, Target};
+use whence::{self, Whence};
+
+use std::fs;
+use std::mem;
+use std::path::PathBuf;
+
+// Matches the default `#![recursion_limit]` of rustc.
+pub const DEFAULT_MAX_DEPTH: usize = 64;
+
+// The crate to expand: a root file, whose modules are read from disk as the
+// compiler would, or source text given directly, named `name` in spans.
+pub enum Input {
+    File(PathBuf),
+    Source { name: String, text: String },
+}
+
+impl Input {
+    // The name of the root file, as spans give it.
+    pub fn name(&self) -> String {
+        match *self {
+            Input::File(ref path) => path.to_string_lossy().into_owned(),
+            Input::Source { ref name, .. } => name.clone(),
+        }
+    }
+}
+
+// How the crate is stepped through.
+#[derive(Clone)]
+pub struct SessionOptions {
+    pub max_steps: Option<usize>,
+    pub max_depth: usize,
+    // Expand a single invocation per step, rather than every pending one.
+    pub one_by_one: bool,
+    pub order: Order,
+    pub filter: MacroFilter,
+    // Comment expanded code with the invocation it came from.
+    pub provenance: bool,
+    pub hygiene: Option<Hygiene>,
+}
+
+impl Default for SessionOptions {
+    fn default() -> SessionOptions {
+        SessionOptions {
+            max_steps: None,
+            max_depth: DEFAULT_MAX_DEPTH,
+            one_by_one: false,
+            order: Order::Outermost,
+            filter: MacroFilter::default(),
+            provenance: false,
+            hygiene: None,
+        }
+    }
+}
+
+// The crate after one step, starting with step 0, the input as parsed. The
+// crate itself is the session's, which keeps only the latest step's.
+pub struct ExpansionStep {
+    pub index: usize,
+    // The printed crate, one (filename, source) pair per module file.
+    pub files: Vec<(String, String)>,
+    // The invocations expanded to reach this step.
+    pub expanded: Vec<String>,
+    // The invocations the next step expands, in source order. The last step
+    // has none.
+    pub pending: Vec<String>,
+    // The invocations and definitions in this step found to be impossible to
+    // expand, which are left as they are in this step and the ones after.
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+// Why the steps ended before every invocation was expanded.
+pub enum StepError {
+    // The step or nesting depth limit was reached: what was reached, and the
+    // backtrace of the most deeply nested pending invocation.
+    Limit(String, Vec<Frame>),
+    // A step could not be expanded or printed.
+    Failed(String),
+}
+
+impl StepError {
+    // The error report, given where the last step can be found.
+    pub fn report(&self, last_step: &str) -> String {
+        match *self {
+            StepError::Limit(ref limit, ref frames) => {
+                backtrace::limit_error(limit, frames, last_step)
+            }
+            StepError::Failed(ref msg) => format!("{}\n  last good step: {}", msg, last_step),
+        }
+    }
+}
+
+// The steps of expanding the crate in `backend`. Iteration ends once no
+// invocations are left, or early with `error()` set.
+pub struct Steps<B: Backend> {
+    backend: B,
+    options: SessionOptions,
+    started: bool,
+    finished: bool,
+    // The invocations pending in the latest step, and what it expanded.
+    pending: Vec<Invocation>,
+    expanded: Vec<String>,
+    // How many of the backend's diagnostics earlier steps have reported.
+    reported: usize,
+    error: Option<StepError>,
+    // The printed files of every step so far; the backend keeps only the
+    // latest step's AST.
+    history: History,
+}
+
+impl<B: Backend> Steps<B> {
+    pub fn new(backend: B, options: SessionOptions) -> Steps<B> {
+        Steps {
+            backend: backend,
+            options: options,
+            started: false,
+            finished: false,
+            pending: Vec::new(),
+            expanded: Vec::new(),
+            reported: 0,
+            error: None,
+            history: History::default(),
+        }
+    }
+
+    // The backend, in the state of the latest step.
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    pub fn error(&self) -> Option<&StepError> {
+        self.error.as_ref()
+    }
+
+    // The printed files of step `index`, if the steps have reached it, rebuilt
+    // from what each step changed.
+    pub fn step_files(&self, index: usize) -> Option<Vec<(String, String)>> {
+        self.history.get(index)
+    }
+
+    // Where the code at `line:col` of the module file `file` in the latest step,
+    // as `print_step` prints it, came from.
+    pub fn whence(&self, file: &str, line: usize, col: usize) -> Result<Whence, String> {
+        let files = try!(self.backend.print_step());
+        let printed = try!(files.iter()
+                                .find(|&&(ref name, _)| same_file(name, file))
+                                .map(|&(_, ref text)| text)
+                                .ok_or(format!("{} is not a module file of {}",
+                                               file, self.backend.filename())));
+        let marked = try!(self.backend.print_outputs());
+        whence::whence(file, self.backend.index(), printed, &marked,
+                       self.backend.events(), line, col)
+    }
+
+    // What the invocation in the input file that `target` picks expands to in
+    // the latest step.
+    pub fn outcome(&self, target: Target) -> Result<Outcome, String> {
+        let marked = try!(self.backend.print_outputs());
+        outcome::outcome(self.backend.filename(), self.backend.index(), &marked,
+                         self.backend.events(), target)
+    }
+
+    fn fail(&mut self, error: StepError) -> Option<ExpansionStep> {
+        self.error = Some(error);
+        self.finished = true;
+        None
+    }
+
+    // Expand the invocations pending in the latest step.
+    fn advance(&mut self) -> Result<(), StepError> {
+        try!(self.check_limits());
+        try!(self.backend.prepare().map_err(StepError::Failed));
+        let pending = mem::replace(&mut self.pending, Vec::new());
+        self.expanded = if self.options.one_by_one {
+            let chosen = choose_invocation(self.options.order, &pending);
+            let mut invocation = format!("{} at {}", pending[chosen].name, pending[chosen].location);
+            if let Some(site) = self.backend.definition_site(chosen) {
+                invocation.push_str(&format!(" (defined at {})", site));
+            }
+            let expanded = try!(self.backend.expand_one(chosen).map_err(StepError::Failed));
+            if expanded { vec![invocation] } else { Vec::new() }
+        } else {
+            let named: Vec<String> = pending.into_iter().enumerate().map(|(i, invocation)| {
+                match self.backend.definition_site(i) {
+                    Some(site) => format!("{} (defined at {})", invocation.name, site),
+                    None => invocation.name,
+                }
+            }).collect();
+            let deferred = try!(self.backend.expand_all().map_err(StepError::Failed));
+            named.into_iter().enumerate()
+                 .filter(|&(i, _)| !deferred.contains(&i))
+                 .map(|(_, name)| name)
+                 .collect()
+        };
+        Ok(())
+    }
+
+    // Stop, reporting the most deeply nested pending invocation, once the step
+    // limit or nesting depth limit has been reached.
+    fn check_limits(&self) -> Result<(), StepError> {
+        let index = self.backend.index();
+        let deepest = self.pending.iter()
+                                  .map(|invocation| &invocation.backtrace)
+                                  .max_by_key(|frames| frames.len())
+                                  .cloned()
+                                  .unwrap_or(Vec::new());
+        let limit = match self.options.max_steps {
+            _ if deepest.len() >= self.options.max_depth => {
+                format!("nesting depth limit of {} reached after step {}",
+                        self.options.max_depth, index)
+            }
+            Some(max) if index >= max => {
+                format!("step limit of {} reached with {} invocation(s) still pending",
+                        max, self.pending.len())
+            }
+            _ => return Ok(()),
+        };
+        Err(StepError::Limit(limit, deepest))
+    }
+}
+
+impl<B: Backend> Iterator for Steps<B> {
+    type Item = ExpansionStep;
+
+    fn next(&mut self) -> Option<ExpansionStep> {
+        if self.finished {
+            return None;
+        }
+        if self.started {
+            if let Err(error) = self.advance() {
+                return self.fail(error);
+            }
+        }
+        self.started = true;
+
+        self.pending = self.backend.pending();
+        let diagnostics = self.backend.diagnostics()[self.reported..].to_vec();
+        self.reported += diagnostics.len();
+        let files = match self.backend.print_step() {
+            Ok(files) => files,
+            Err(msg) => return self.fail(StepError::Failed(msg)),
+        };
+        self.history.push(&files);
+        if self.pending.is_empty() {
+            self.finished = true;
+        }
+        Some(ExpansionStep {
+            index: self.backend.index(),
+            files: files,
+            expanded: mem::replace(&mut self.expanded, Vec::new()),
+            pending: self.pending.iter().map(|invocation| invocation.name.clone()).collect(),
+            diagnostics: diagnostics,
+        })
+    }
+}
+
+// The pending invocation to expand next when expanding one per step: the least or
+// most deeply nested one, taking the first in source order on ties.
+fn choose_invocation(order: Order, pending: &[Invocation]) -> usize {
+    let mut chosen = 0;
+    for (i, invocation) in pending.iter().enumerate() {
+        let depth = invocation.backtrace.len();
+        let better = match order {
+            Order::Outermost => depth < pending[chosen].backtrace.len(),
+            Order::Innermost => depth > pending[chosen].backtrace.len(),
+        };
+        if better {
+            chosen = i;
+        }
+    }
+    chosen
+}
+
+// Whether the paths `a` and `b` name the same file, as they may be written
+// differently.
+fn same_file(a: &str, b: &str) -> bool {
+    if a == b {
+        return true;
+    }
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
@@ -0,0 +1,224 @@
+// The span fixing shared by the backends. The expander gives the code each
+// step produces spans relative to the step, so code from an earlier step would
+// lose its call site; walking the new crate maps every span back to one whose
+// expansion chains through the original call sites, recording each expansion
+// as an event on the way.
+//
+// The backends use different `syntax` crates with the same API, so this is a
+// macro expanded in each of them, where `syntax` names the backend's crate.
+// `span_fixing!(ExpandData)` implements the span map and the `Folder` for a
+// struct with `cx`, `index`, `span_map`, `chained`, `events`, `event_ids`,
+// `item_path` and `in_item` fields and a `found_invocation` method, as
+// `ExpandData` has in both. The fold hands every macro left in what it folds to
+// `found_invocation`, in source order, with `item_path` the path to the item of
+// a module it is in, so that the backend learns what is pending without a walk
+// of its own.
+#[macro_export]
+macro_rules! span_fixing {
+    ($data:ident) => {
+        mod span_fixing {
+            use super::$data;
+
+            use syntax::ast;
+            use syntax::codemap::{Span, ExpnId, ExpnInfo, NO_EXPANSION};
+            use syntax::fold::{self, Folder};
+            use syntax::ptr::P;
+            use syntax::util::small_vector::SmallVector;
+
+            use $crate::backtrace::Frame;
+            use $crate::events::{self, Event, Position, SpanInfo};
+            use $crate::markers::Side;
+
+            use std::mem;
+
+            impl<'a> $data<'a> {
+                // Whether `span` needs no fixing: it is in the input, or in code
+                // from an expansion already recorded.
+                pub fn fixed(&self, span: Span) -> bool {
+                    span.expn_id == NO_EXPANSION || self.event_ids.contains_key(&span.expn_id)
+                }
+
+                // Fold `item`, the item of a module at `path`, the index of each
+                // item on the way down from the root module.
+                pub fn fold_module_item(&mut self, item: P<ast::Item>, path: &[usize])
+                                        -> SmallVector<P<ast::Item>> {
+                    let is_mod = match item.node {
+                        ast::ItemKind::Mod(_) => true,
+                        _ => false,
+                    };
+                    let outer_path = mem::replace(&mut self.item_path, path.to_vec());
+                    let outer_in_item = mem::replace(&mut self.in_item, !is_mod);
+                    let items = self.fold_item(item);
+                    self.item_path = outer_path;
+                    self.in_item = outer_in_item;
+                    items
+                }
+
+                pub fn insert(&mut self, span: Span) {
+                    if span.expn_id == NO_EXPANSION {
+                        return;
+                    }
+
+                    let key_sp = Span {
+                        lo: span.lo,
+                        hi: span.hi,
+                        expn_id: NO_EXPANSION
+                    };
+                    let callsite = self.cx.codemap().with_expn_info(span.expn_id,
+                                                                    |ei| ei.map(|ei| ei.call_site.clone()));
+                    if callsite.is_none() {
+                        panic!("Callsite not found!");
+                    }
+                    let mut callsite = callsite.unwrap();
+
+                    if !self.span_map.contains_key(&callsite) {
+                        self.span_map.insert(key_sp, span);
+                        self.record_event(span.expn_id);
+                        return;
+                    }
+
+                    if let Some(&new_id) = self.chained.get(&span.expn_id) {
+                        self.span_map.insert(key_sp, Span { expn_id: new_id, .. span });
+                        return;
+                    }
+
+                    let callee = self.cx.codemap().with_expn_info(span.expn_id,
+                                                                  |ei| ei.map(|ei| ei.callee.clone()));
+                    if callee.is_none() {
+                        panic!("Callee not found!");
+                    }
+                    let callee = callee.unwrap();
+
+                    callsite = self.span_map.get(&callsite).unwrap().clone();
+                    let info = ExpnInfo {
+                        call_site: callsite,
+                        callee: callee
+                    };
+                    let new_id = self.cx.codemap().record_expansion(info);
+                    self.chained.insert(span.expn_id, new_id);
+                    self.span_map.insert(key_sp, Span { expn_id: new_id, .. span });
+                    self.record_event(new_id);
+                }
+
+                // Log the expansion `id` the first time it is seen. Spans are fixed while
+                // the step an expansion produced is being built, hence `index + 1`.
+                pub fn record_event(&mut self, id: ExpnId) {
+                    if self.event_ids.contains_key(&id) {
+                        return;
+                    }
+                    let info = self.cx.codemap().with_expn_info(id, |ei| {
+                        ei.map(|ei| (ei.callee.name(), ei.call_site.clone(), ei.callee.span.clone()))
+                    });
+                    let (name, call_site, callee) = match info {
+                        Some(info) => info,
+                        None => return,
+                    };
+                    let parent = self.event_ids.get(&call_site.expn_id).cloned();
+                    let event = Event {
+                        id: self.events.len(),
+                        step: self.index + 1,
+                        name: format!("{}!", name),
+                        call_site: self.span_info(call_site),
+                        callee: callee.map(|span| self.span_info(span)),
+                        output: None,
+                        parent: parent,
+                        root: parent.map_or(self.events.len(), |parent| self.events[parent].root),
+                        rule: None,
+                    };
+                    self.event_ids.insert(id, event.id);
+                    self.events.push(event);
+                }
+
+                pub fn span_info(&self, span: Span) -> SpanInfo {
+                    let lo = self.cx.codemap().lookup_char_pos(span.lo);
+                    let hi = self.cx.codemap().lookup_char_pos(span.hi);
+                    SpanInfo {
+                        file: lo.file.name.clone(),
+                        lo: Position { line: lo.line, col: lo.col.0 + 1 },
+                        hi: Position { line: hi.line, col: hi.col.0 + 1 },
+                    }
+                }
+
+                // The event of the expansion that produced `span`, and the side of its
+                // invocation the code was written on.
+                pub fn mark(&self, span: Span) -> Option<(usize, Side)> {
+                    self.event_ids.get(&span.expn_id).map(|&id| {
+                        (id, events::side(&self.events, id, &self.span_info(span)))
+                    })
+                }
+
+                pub fn get(&mut self, span: Span) -> Span {
+                    let key_sp = Span { expn_id: NO_EXPANSION, .. span };
+                    return self.span_map.get(&key_sp).unwrap_or(&span).clone();
+                }
+
+                // Walk the call sites of the expansions that produced `span`, innermost first.
+                pub fn backtrace(&self, span: Span) -> Vec<Frame> {
+                    let key_sp = Span { expn_id: NO_EXPANSION, .. span };
+                    let mut span = self.span_map.get(&key_sp).unwrap_or(&span).clone();
+                    let mut frames = Vec::new();
+                    while span.expn_id != NO_EXPANSION {
+                        let info = self.cx.codemap().with_expn_info(span.expn_id, |ei| {
+                            ei.map(|ei| (ei.callee.name(), ei.call_site.clone()))
+                        });
+                        let (name, call_site) = match info {
+                            Some(info) => info,
+                            None => break,
+                        };
+                        if call_site.expn_id == span.expn_id {
+                            break;
+                        }
+                        frames.push(Frame {
+                            name: format!("{}!", name),
+                            call_site: self.cx.codemap().span_to_string(call_site),
+                        });
+                        span = call_site;
+                    }
+                    frames
+                }
+            }
+
+            // Walk over the AST of what a step changed to patch up spans. The noop
+            // folds pass every span in every node, from blocks and match arms to
+            // paths, generics and attributes, through `new_span`, once each. Code
+            // from earlier steps was fixed when those were folded, and the spans
+            // of expansions already recorded are passed over, so only the code
+            // this step produced is looked up.
+            impl<'a> Folder for $data<'a> {
+                fn new_span(&mut self, span: Span) -> Span {
+                    if self.fixed(span) {
+                        return span;
+                    }
+
+                    self.insert(span);
+                    self.get(span)
+                }
+
+                // The items of a module are folded one at a time, as
+                // `noop_fold_mod` does, so that the path to each is known. The
+                // items of a module inside another item, such as a function, are
+                // part of that item.
+                fn fold_mod(&mut self, module: ast::Mod) -> ast::Mod {
+                    if self.in_item {
+                        return fold::noop_fold_mod(module, self);
+                    }
+                    let ast::Mod { inner, items } = module;
+                    let inner = self.new_span(inner);
+                    let mut folded = Vec::new();
+                    for item in items {
+                        let mut path = self.item_path.clone();
+                        path.push(folded.len());
+                        folded.extend(self.fold_module_item(item, &path));
+                    }
+                    ast::Mod { inner: inner, items: folded }
+                }
+
+                fn fold_mac(&mut self, mac: ast::Mac) -> ast::Mac {
+                    let mac = fold::noop_fold_mac(mac, self);
+                    self.found_invocation(&mac);
+                    mac
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,46 @@
+// Colours for `trace --color`: code written in a macro's body and code written
+// in an invocation's arguments are printed in different colours, and the
+// input's own code in the terminal's.
+
+use markers::{self, Side, OPEN, SEP, CLOSE};
+
+const RESET: &'static str = "\x1b[0m";
+
+fn colour(side: Side) -> &'static str {
+    match side {
+        Side::Callee => "\x1b[36m",
+        Side::CallSite => "\x1b[32m",
+    }
+}
+
+// Turn the markers in a printed step into ANSI colours, by the side of the
+// innermost marked node.
+pub fn colorize(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut open: Vec<Side> = Vec::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        let before = open.last().cloned();
+        match c {
+            OPEN => {
+                let label: String = chars.by_ref().take_while(|&c| c != SEP).collect();
+                open.push(markers::parse_label(&label).1);
+            }
+            CLOSE => {
+                open.pop();
+            }
+            _ => {
+                out.push(c);
+                continue;
+            }
+        }
+        let after = open.last().cloned();
+        if after != before {
+            out.push_str(after.map_or(RESET, colour));
+        }
+    }
+    if !open.is_empty() {
+        out.push_str(RESET);
+    }
+    out
+}
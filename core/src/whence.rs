@@ -0,0 +1,123 @@
+// Where the code at a position in a printed step came from, for `whence`: the
+// expansions it came through, innermost first, down to the invocation written
+// in the input file.
+
+use rustc_serialize::json;
+
+use diff;
+use events::{Event, SpanInfo};
+use markers::{self, Side};
+
+// One expansion the code came through.
+pub struct Expansion {
+    // The id of its event.
+    pub event: usize,
+    // The macro, with its `!`.
+    pub name: String,
+    pub call_site: SpanInfo,
+    // Where the macro is defined, when known.
+    pub definition: Option<SpanInfo>,
+}
+
+encodable!(Expansion { event, name, call_site, definition });
+
+pub struct Whence {
+    pub file: String,
+    pub step: usize,
+    pub line: usize,
+    pub col: usize,
+    // `callee` when the code was written in the body of the innermost macro,
+    // `call_site` when in the arguments of its invocation, `None` when it was
+    // written in the input file.
+    pub side: Option<String>,
+    pub expansions: Vec<Expansion>,
+}
+
+encodable!(Whence { file, step, line, col, side, expansions });
+
+fn location(span: &SpanInfo) -> String {
+    format!("{}:{}:{}", span.file, span.lo.line, span.lo.col)
+}
+
+impl Whence {
+    // The report printed for it: what wrote the code, then one line for
+    // each expansion.
+    pub fn report(&self) -> String {
+        let position = format!("{}:{}:{} in step {}", self.file, self.line, self.col, self.step);
+        let mut report = match (self.side.as_ref(), self.expansions.first()) {
+            (Some(side), Some(innermost)) if side == Side::CallSite.name() => {
+                format!("{} was written in the arguments of {}", position, innermost.name)
+            }
+            (_, Some(innermost)) => format!("{} was written in the body of {}", position, innermost.name),
+            (_, None) => format!("{} was written in the input file", position),
+        };
+        for expansion in &self.expansions {
+            report.push_str(&format!("\n  {} at {}", expansion.name, location(&expansion.call_site)));
+            if let Some(ref definition) = expansion.definition {
+                report.push_str(&format!(", defined at {}", location(definition)));
+            }
+        }
+        report
+    }
+
+    pub fn to_json(&self) -> String {
+        format!("{}\n", json::as_pretty_json(self))
+    }
+}
+
+// The byte offset in `text` of 1-based `line` and `col`, which counts
+// characters, if the line is that long.
+fn offset(text: &str, line: usize, col: usize) -> Option<usize> {
+    let mut start = 0;
+    for (i, text_line) in text.split('\n').enumerate() {
+        if i + 1 == line {
+            return if col >= 1 && col <= text_line.chars().count() + 1 {
+                Some(start + text_line.chars().take(col - 1).map(|c| c.len_utf8()).sum::<usize>())
+            } else {
+                None
+            };
+        }
+        start += text_line.len() + 1;
+    }
+    None
+}
+
+// Where the code at `line:col` of `printed`, the module file `file` of step
+// `step` as the backend's `print_step` printed it, came from, given `marked`,
+// the whole step as its `print_outputs` prints it, and every expansion so far.
+// The position is carried over to `marked` by diffing their tokens, which
+// differ in line breaks, in comments and in the other modules' code.
+pub fn whence(file: &str,
+              step: usize,
+              printed: &str,
+              marked: &str,
+              events: &[Event],
+              line: usize,
+              col: usize) -> Result<Whence, String> {
+    let (text, _, segments) = markers::strip_markers(marked);
+    let at = try!(offset(printed, line, col).ok_or(format!("{} has no position {}:{} in step {}",
+                                                            file, line, col, step)));
+    let at = try!(diff::map_offset(printed, &text, at).ok_or(format!(
+        "{}:{}:{} in step {} is not in the code of the step", file, line, col, step)));
+    let segment = segments.iter().find(|segment| segment.lo <= at && at < segment.hi);
+    let mut expansions = Vec::new();
+    let mut expn = segment.map(|segment| segment.id);
+    while let Some(id) = expn {
+        let event = &events[id];
+        expansions.push(Expansion {
+            event: id,
+            name: event.name.clone(),
+            call_site: event.call_site.clone(),
+            definition: event.callee.clone(),
+        });
+        expn = event.parent;
+    }
+    Ok(Whence {
+        file: file.to_owned(),
+        step: step,
+        line: line,
+        col: col,
+        side: segment.map(|segment| segment.side.name().to_owned()),
+        expansions: expansions,
+    })
+}
@@ -0,0 +1,815 @@
+// The syntex frontend: steps through the expansion of a crate with syntex's
+// expander and formats each step with rustfmt. `ExpansionSession` is the library
+// entry point, and `run` the one the `expander` binary uses.
+
+#[macro_use]
+extern crate macro_expander_core;
+extern crate rustfmt;
+extern crate syntex_syntax as syntax;
+
+annotate!();
+attempt!();
+hygiene!();
+
+use syntax::ast;
+use syntax::ext::base::{ExtCtxt, DummyMacroLoader};
+use syntax::ext::expand;
+use syntax::ext::expand::{ExpansionConfig, MacroExpander};
+use syntax::codemap::{BytePos, CodeMap, Span, Spanned, ExpnId};
+use syntax::errors::{FatalError, Handler};
+use syntax::errors::emitter::{ColorConfig};
+use syntax::fold::Folder;
+use syntax::parse::{self, token, ParseSess};
+use syntax::print::pprust::{self, print_crate, NoAnn, PpAnn};
+use syntax::ptr::P;
+
+use rustfmt::filemap::FileMap;
+use rustfmt::config::{Config, WriteMode};
+use rustfmt::modules::list_files;
+use rustfmt::visitor::FmtVisitor;
+
+use macro_expander_core::cli::{Hygiene, MacroFilter, Options};
+use macro_expander_core::engine::{self, Backend, Invocation};
+use macro_expander_core::events::{Diagnostic, Event};
+use macro_expander_core::html::Origins;
+use macro_expander_core::outcome::{Outcome, Target};
+use macro_expander_core::session::{self, Input, SessionOptions, StepError, Steps};
+use macro_expander_core::whence::Whence;
+
+use annotate::{MarkingAnn, ProvenanceAnn};
+use hygiene::MarksAnn;
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::mem;
+use std::rc::Rc;
+
+// Small macro to simplify setting the full-expansion closures to the identity closure.
+macro_rules! set_expander_fns {
+    ($expander:ident,
+        $( $expand:ident ),*) => {{
+        $( $expander.$expand = Rc::new(Box::new(|_, node| node )); )*
+    }}
+}
+
+struct ExpandData<'a> {
+    filename: String,
+    config: Config,
+    cx: ExtCtxt<'a>,
+    // The crate at the current step. Earlier steps are kept by `Steps` only as
+    // printed text.
+    krate: ast::Crate,
+    // Invocations of macros the filter excludes are never expanded.
+    filter: MacroFilter,
+    // Whether printed steps comment expanded code with where it came from.
+    provenance: bool,
+    // How printed steps show the syntax contexts of identifiers, if at all.
+    hygiene: Option<Hygiene>,
+    index: usize,
+    span_map: HashMap<Span, Span>,
+    // The chained id given to each expansion nested in an earlier one, by the id
+    // the expander gave it, so that each expansion is recorded once.
+    chained: HashMap<ExpnId, ExpnId>,
+    // Every expansion seen so far, and its index in `events` by expansion id.
+    events: Vec<Event>,
+    event_ids: HashMap<ExpnId, usize>,
+    // The worklist: the invocations pending in the current step, in source
+    // order, as the span fixing fold found them.
+    invocations: Vec<Pending>,
+    // The paths to the items holding a `macro_rules!` definition the expander
+    // has yet to register, which it is handed at the next step.
+    defining: Vec<Vec<usize>>,
+    // The path to the item of a module the span fixing fold is in, and whether
+    // it is within that item rather than in a module.
+    item_path: Vec<usize>,
+    in_item: bool,
+    // The invocations the expander gave up on, by source position, which are
+    // left as they are from then on, and the diagnostic recorded for each.
+    failed: HashSet<(BytePos, BytePos)>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+// An invocation on the worklist, with the path to the item of a module it is
+// in: the index of each item on the way down from the root module. A step that
+// expands it hands the expander that item, and changes nothing else.
+#[derive(Clone)]
+struct Pending {
+    name: String,
+    span: Span,
+    item: Vec<usize>,
+}
+
+// What the expander made of a step: the items it was handed, expanded, and a
+// stub in place of each of the others.
+struct Expanded {
+    krate: ast::Crate,
+    // The paths of the invocations hidden in the items it was handed, by
+    // source position.
+    held: HashMap<(BytePos, BytePos), ast::Path>,
+    // The path to the item each stub stands for, by the stub's name.
+    stubs: HashMap<ast::Name, Vec<usize>>,
+}
+
+impl<'a> ExpandData<'a> {
+    fn new(sess: &'a ParseSess,
+           loader: &'a mut DummyMacroLoader,
+           input: &Input,
+           filter: MacroFilter,
+           provenance: bool,
+           hygiene: Option<Hygiene>) -> Result<ExpandData<'a>, String> {
+        let filename = input.name();
+        let parsed = match *input {
+            Input::File(ref path) => {
+                if !path.is_file() {
+                    return Err(format!("no such file: {}", filename));
+                }
+                parse::parse_crate_from_file(path, Vec::new(), sess)
+            }
+            Input::Source { ref name, ref text } => {
+                parse::parse_crate_from_source_str(name.clone(), text.clone(), Vec::new(), sess)
+            }
+        };
+
+        let mut config = Config::default();
+        config.write_mode = WriteMode::Overwrite;
+
+        let ex_cfg = ExpansionConfig::default(filename.clone());
+        let krate = match parsed {
+            Ok(krate) => krate,
+            Err(mut diag) => {
+                diag.emit();
+                return Err(format!("could not parse {}", filename));
+            }
+        };
+        let ecx = ExtCtxt::new(sess,
+                               krate.config.clone(),
+                               ex_cfg,
+                               loader);
+        let mut data = ExpandData {
+            filename: filename,
+            config: config,
+            cx: ecx,
+            krate: krate.clone(),
+            filter: filter,
+            provenance: provenance,
+            hygiene: hygiene,
+            index: 0,
+            span_map: HashMap::new(),
+            chained: HashMap::new(),
+            events: Vec::new(),
+            event_ids: HashMap::new(),
+            invocations: Vec::new(),
+            defining: Vec::new(),
+            item_path: Vec::new(),
+            in_item: false,
+            failed: HashSet::new(),
+            diagnostics: Vec::new(),
+        };
+        // The crate as parsed is folded once, as what each step changes is, to
+        // start the worklist.
+        data.krate = data.fold_crate(krate);
+        Ok(data)
+    }
+
+    // Expand every pending invocation, returning those left unexpanded.
+    fn expand_crate(&mut self) -> Result<HashSet<usize>, String> {
+        self.expand_selected(None)
+    }
+
+    // Expand only the `chosen`th invocation on the worklist, returning whether
+    // it was expanded.
+    fn expand_invocation(&mut self, chosen: usize) -> Result<bool, String> {
+        self.expand_selected(Some(chosen)).map(|skipped| !skipped.contains(&chosen))
+    }
+
+    // The expander takes what it expands, so it is given copies of the items it
+    // needs, and the current step is left as it was if the expander gives up.
+    // The invocations it gives up on when expanding them alone are then left as
+    // they are from this step on, each with a diagnostic, and the step is
+    // expanded again without them.
+    fn expand_selected(&mut self, chosen: Option<usize>) -> Result<HashSet<usize>, String> {
+        let mut skipped = HashSet::new();
+        let (expanded, failing) = match self.run_expander(chosen, &skipped) {
+            Ok(expanded) => (expanded, Vec::new()),
+            Err(msg) => {
+                let failing = self.failing(chosen);
+                if failing.is_empty() {
+                    return Err(msg);
+                }
+                skipped.extend(failing.iter().cloned());
+                (try!(self.run_expander(chosen, &skipped)), failing)
+            }
+        };
+
+        let failing: Vec<Pending> = failing.into_iter().map(|i| self.invocations[i].clone()).collect();
+        for pending in &failing {
+            self.failed.insert((pending.span.lo, pending.span.hi));
+        }
+        self.commit(expanded);
+        for pending in failing {
+            let info = self.span_info(pending.span);
+            self.diagnostics.push(Diagnostic {
+                step: self.index,
+                name: pending.name,
+                span: info,
+                message: "the compiler's expander gave up on it; its error is reported above".to_owned(),
+                closest: None,
+            });
+        }
+        Ok(skipped)
+    }
+
+    // The selected invocations that the expander gives up on by themselves;
+    // when only one was selected, that one.
+    fn failing(&mut self, chosen: Option<usize>) -> Vec<usize> {
+        if let Some(chosen) = chosen {
+            return vec![chosen];
+        }
+        let candidates: Vec<usize> = (0..self.invocations.len()).collect();
+        self.bisect(&candidates, &HashSet::new())
+    }
+
+    // The invocations among `candidates`, which the expander gives up on when
+    // they are expanded together with every one in `hidden` hidden, that it
+    // gives up on by themselves. Each half is expanded on its own and only a
+    // half the expander gives up on is halved again, so `k` failing invocations
+    // among `n` take about `2k log n` runs rather than `n`.
+    fn bisect(&mut self, candidates: &[usize], hidden: &HashSet<usize>) -> Vec<usize> {
+        if candidates.len() <= 1 {
+            return candidates.to_vec();
+        }
+        let (first, second) = candidates.split_at(candidates.len() / 2);
+        let mut failing = Vec::new();
+        for &(half, rest) in &[(first, second), (second, first)] {
+            let mut hidden = hidden.clone();
+            hidden.extend(rest.iter().cloned());
+            if self.run_expander(None, &hidden).is_err() {
+                failing.extend(self.bisect(half, &hidden));
+            }
+        }
+        failing
+    }
+
+    // The selected invocations on the worklist, each by the path to its item and
+    // its index among the pending invocations in that item.
+    fn selected(&self, chosen: Option<usize>, skipped: &HashSet<usize>) -> HashSet<(Vec<usize>, usize)> {
+        let mut selected = HashSet::new();
+        let mut seen = 0;
+        for (i, pending) in self.invocations.iter().enumerate() {
+            seen = if i > 0 && self.invocations[i - 1].item == pending.item { seen + 1 } else { 0 };
+            if chosen.map_or(true, |chosen| chosen == i) && !skipped.contains(&i) {
+                selected.insert((pending.item.clone(), seen));
+            }
+        }
+        selected
+    }
+
+    // Run one step of the expander over copies of the items of the current step
+    // that hold a selected invocation or a definition, with every other
+    // invocation in them hidden, and a stub in place of every other item. Fails
+    // if the expander gives up.
+    fn run_expander(&mut self, chosen: Option<usize>, skipped: &HashSet<usize>) -> Result<Expanded, String> {
+        let (krate, held, stubs) = {
+            let selected = self.selected(chosen, skipped);
+            let mut hider = InvocationHider::new(&self.filter, &self.failed, selected, &self.defining);
+            let krate = hider.hide_crate(&self.krate);
+            (krate, hider.held, hider.stubs)
+        };
+
+        // The expander gives up midway through the crate, so the context is put
+        // back as it was for the next attempt.
+        let snapshot = attempt::Snapshot::take(&mut self.cx);
+        let expanded = engine::catch_fatal::<FatalError, _, _>(|| {
+            let mut expander = MacroExpander::new(&mut self.cx);
+
+            set_expander_fns!(expander,
+                                expand_pat,
+                                expand_type,
+                                expand_expr,
+                                expand_stmt,
+                                expand_item,
+                                expand_impl_item,
+                                expand_trait_item,
+                                expand_opt_expr);
+
+            expand::expand_crate_with_expander(&mut expander, Vec::new(), krate).0
+        });
+        match expanded {
+            Ok(krate) => Ok(Expanded { krate: krate, held: held, stubs: stubs }),
+            Err(()) => {
+                snapshot.restore(&mut self.cx);
+                Err(format!("could not expand step {}; the error is reported above", self.index + 1))
+            }
+        }
+    }
+
+    // Make what the expander made of the current step the next step. The items
+    // it was not handed are put back in place of their stubs, and only the rest
+    // is folded, to put back the paths of the invocations hidden in it and fix
+    // its spans. The worklist is rebuilt on the way, in source order: the
+    // invocations in the items put back are kept, and those in the rest are
+    // found by the fold.
+    fn commit(&mut self, expanded: Expanded) {
+        let Expanded { krate, held, stubs } = expanded;
+        let stubbed: HashSet<Vec<usize>> = stubs.values().cloned().collect();
+        let mut originals = HashMap::new();
+        let items = mem::replace(&mut self.krate.module.items, Vec::new());
+        take_unchanged(items, &mut Vec::new(), &stubbed, &mut originals);
+        let mut merge = Merge {
+            stubs: stubs,
+            originals: originals,
+            restorer: InvocationRestorer { held: held },
+            old: mem::replace(&mut self.invocations, Vec::new()),
+            next: 0,
+        };
+        self.defining.clear();
+        let module = self.merge_mod(krate.module, &mut Vec::new(), &mut merge);
+        self.krate = ast::Crate { module: module, .. krate };
+        self.index += 1;
+    }
+
+    // The items of `module`, the module at `path` as the expander left it, with
+    // the items its stubs stand for put back and the rest folded. A module the
+    // step kept, whose spans were fixed before, is gone through item by item;
+    // one it produced is folded whole.
+    fn merge_mod(&mut self, module: ast::Mod, path: &mut Vec<usize>, merge: &mut Merge) -> ast::Mod {
+        let ast::Mod { inner, items } = module;
+        let mut merged = Vec::new();
+        for item in items {
+            path.push(merged.len());
+            match merge.stubs.remove(&item.ident.name) {
+                Some(from) => {
+                    merged.push(merge.originals.remove(&from).expect("a stub stands for an item"));
+                    let carried = merge.carry(&from, path);
+                    self.invocations.extend(carried);
+                }
+                None if is_module(&item) && self.fixed(item.span) => {
+                    merged.push(item.map(|mut item| {
+                        item.node = match item.node {
+                            ast::ItemKind::Mod(module) => ast::ItemKind::Mod(self.merge_mod(module, path, merge)),
+                            node => node,
+                        };
+                        item
+                    }));
+                }
+                None => {
+                    let item = merge.restorer.fold_item(item).expect_one("an item folds to one item");
+                    merged.extend(self.fold_module_item(item, path));
+                }
+            }
+            path.pop();
+        }
+        ast::Mod { inner: inner, items: merged }
+    }
+
+    // Note `mac` on the worklist if it is an invocation the filter lets through,
+    // or its item if it is a definition the expander has yet to register.
+    fn found_invocation(&mut self, mac: &ast::Mac) {
+        if !is_invocation(mac) {
+            if !mac.node.path.segments.is_empty() {
+                self.defining.push(self.item_path.clone());
+            }
+            return;
+        }
+        let name = pprust::path_to_string(&mac.node.path);
+        if !self.filter.allows(&name) || self.failed.contains(&(mac.span.lo, mac.span.hi)) {
+            return;
+        }
+        self.invocations.push(Pending {
+            name: format!("{}!", name),
+            span: mac.span,
+            item: self.item_path.clone(),
+        });
+    }
+
+    // Format every module file of the crate at the current step.
+    fn format_step(&self) -> FileMap {
+        let mut fm = FileMap::new();
+        for (path, module) in list_files(&self.krate, self.cx.codemap()) {
+            let path = path.to_str().unwrap();
+            let mut visitor = FmtVisitor::from_codemap(self.cx.parse_sess, &self.config);
+            visitor.format_separate_mod(module);
+            fm.push((path.to_owned(), visitor.buffer));
+        }
+        fm
+    }
+
+    // Pretty-print `krate`, the current step or a copy of it, whole through
+    // libsyntax's printer rather than rustfmt, which takes no annotations.
+    // The root file's source comes from the codemap, so that input given as a
+    // string prints the same as a file on disk.
+    fn print_with(&self, krate: &ast::Crate, ann: &PpAnn) -> Result<String, io::Error> {
+        let fm = self.cx.codemap().lookup_byte_offset(krate.module.inner.lo).fm;
+        let source = try!(fm.src.clone().ok_or(io::Error::new(io::ErrorKind::NotFound,
+                                                              format!("no source for {}", fm.name))));
+        let mut out = Vec::new();
+        {
+            let handler = &self.cx.parse_sess().span_diagnostic;
+            let mut src = source.as_bytes();
+            try!(print_crate(self.cx.codemap(), handler, krate,
+                             self.filename.clone(), &mut src, Box::new(&mut out), ann, false));
+        }
+        Ok(String::from_utf8_lossy(&out).into_owned())
+    }
+}
+
+span_fixing!(ExpandData);
+
+// Whether `mac` is a macro call, rather than a `macro_rules!` definition or the
+// placeholder an expanded definition leaves. The folders all count invocations
+// with it, so that they count alike.
+fn is_invocation(mac: &ast::Mac) -> bool {
+    !mac.node.path.segments.is_empty() && pprust::path_to_string(&mac.node.path) != "macro_rules"
+}
+
+fn is_module(item: &ast::Item) -> bool {
+    match item.node {
+        ast::ItemKind::Mod(_) => true,
+        _ => false,
+    }
+}
+
+// Whether `module` has a `macro_rules!` definition among its items, or among
+// those of the modules in it.
+fn defines_macros(module: &ast::Mod) -> bool {
+    module.items.iter().any(|item| match item.node {
+        ast::ItemKind::Mod(ref module) => defines_macros(module),
+        ast::ItemKind::Mac(ref mac) => !is_invocation(mac),
+        _ => false,
+    })
+}
+
+// Builds the crate handed to the expander from the current step. It holds
+// copies of the items with a selected invocation or a definition in them, and
+// the modules on the way to them, and a stub in place of every other item,
+// which the expander passes over. The definitions in modules are always handed
+// over, with the modules they are in, so that the macros they define stay
+// available.
+//
+// In the items it copies, it hides invocations from the expander by emptying
+// their paths, like the placeholders left by `macro_rules!` definitions: those
+// of macros the filter excludes, those the expander gave up on, and those not
+// selected, which are skipped in this step or, when expanding one invocation
+// per step, all but the chosen one.
+struct InvocationHider<'f> {
+    filter: &'f MacroFilter,
+    failed: &'f HashSet<(BytePos, BytePos)>,
+    // The selected invocations, by the path to their item and their index
+    // among the pending invocations in it.
+    selected: HashSet<(Vec<usize>, usize)>,
+    // The paths to the items with a selected invocation or a definition in
+    // them, and to every module on the way.
+    touched: HashSet<Vec<usize>>,
+    // The item being copied, and how many pending invocations it has had.
+    item: Vec<usize>,
+    seen: usize,
+    // The paths of the hidden invocations, by source position.
+    held: HashMap<(BytePos, BytePos), ast::Path>,
+    // The path to the item each stub stands for, by the stub's name.
+    stubs: HashMap<ast::Name, Vec<usize>>,
+}
+
+impl<'f> InvocationHider<'f> {
+    fn new(filter: &'f MacroFilter,
+           failed: &'f HashSet<(BytePos, BytePos)>,
+           selected: HashSet<(Vec<usize>, usize)>,
+           defining: &[Vec<usize>]) -> InvocationHider<'f> {
+        let mut touched = HashSet::new();
+        for path in selected.iter().map(|&(ref path, _)| path).chain(defining) {
+            for end in 0..path.len() + 1 {
+                touched.insert(path[..end].to_vec());
+            }
+        }
+        InvocationHider {
+            filter: filter,
+            failed: failed,
+            selected: selected,
+            touched: touched,
+            item: Vec::new(),
+            seen: 0,
+            held: HashMap::new(),
+            stubs: HashMap::new(),
+        }
+    }
+
+    fn hide_crate(&mut self, krate: &ast::Crate) -> ast::Crate {
+        ast::Crate {
+            module: self.hide_mod(&krate.module, &mut Vec::new()),
+            attrs: krate.attrs.clone(),
+            config: krate.config.clone(),
+            span: krate.span,
+            exported_macros: krate.exported_macros.clone(),
+        }
+    }
+
+    fn hide_mod(&mut self, module: &ast::Mod, path: &mut Vec<usize>) -> ast::Mod {
+        let mut items = Vec::new();
+        for (i, item) in module.items.iter().enumerate() {
+            path.push(i);
+            items.push(self.hide_item(item, path));
+            path.pop();
+        }
+        ast::Mod { inner: module.inner, items: items }
+    }
+
+    fn hide_item(&mut self, item: &P<ast::Item>, path: &[usize]) -> P<ast::Item> {
+        let touched = self.touched.contains(path);
+        match item.node {
+            ast::ItemKind::Mod(ref module) if touched || defines_macros(module) => {
+                let mut path = path.to_vec();
+                return P(ast::Item {
+                    ident: item.ident,
+                    attrs: item.attrs.clone(),
+                    id: item.id,
+                    node: ast::ItemKind::Mod(self.hide_mod(module, &mut path)),
+                    vis: item.vis.clone(),
+                    span: item.span,
+                });
+            }
+            ast::ItemKind::Mod(_) => {}
+            ast::ItemKind::Mac(ref mac) if !is_invocation(mac) => return item.clone(),
+            _ if touched => {
+                self.item = path.to_vec();
+                self.seen = 0;
+                return self.fold_item(item.clone()).expect_one("an item folds to one item");
+            }
+            _ => {}
+        }
+        let ident = token::gensym_ident("<unchanged>");
+        self.stubs.insert(ident.name, path.to_vec());
+        P(ast::Item {
+            ident: ident,
+            attrs: Vec::new(),
+            id: item.id,
+            node: ast::ItemKind::ExternCrate(None),
+            vis: ast::Visibility::Inherited,
+            span: item.span,
+        })
+    }
+}
+
+impl<'f> Folder for InvocationHider<'f> {
+    fn fold_mac(&mut self, mac: ast::Mac) -> ast::Mac {
+        if !is_invocation(&mac) {
+            return mac;
+        }
+        let name = pprust::path_to_string(&mac.node.path);
+        if self.filter.allows(&name) && !self.failed.contains(&(mac.span.lo, mac.span.hi)) {
+            self.seen += 1;
+            if self.selected.contains(&(self.item.clone(), self.seen - 1)) {
+                return mac;
+            }
+        }
+        self.held.insert((mac.span.lo, mac.span.hi), mac.node.path.clone());
+        let path = ast::Path { segments: Vec::new(), .. mac.node.path.clone() };
+        Spanned { node: ast::Mac_ { path: path, .. mac.node }, span: mac.span }
+    }
+}
+
+// Move the items at the paths in `stubbed` out of `items`, the items of the
+// module at `path`, into `found`.
+fn take_unchanged(items: Vec<P<ast::Item>>,
+                  path: &mut Vec<usize>,
+                  stubbed: &HashSet<Vec<usize>>,
+                  found: &mut HashMap<Vec<usize>, P<ast::Item>>) {
+    for (i, item) in items.into_iter().enumerate() {
+        path.push(i);
+        if stubbed.contains(&path[..]) {
+            found.insert(path.clone(), item);
+        } else {
+            item.and_then(|item| {
+                if let ast::ItemKind::Mod(module) = item.node {
+                    take_unchanged(module.items, path, stubbed, found);
+                }
+            });
+        }
+        path.pop();
+    }
+}
+
+// What `commit` builds the next step from, besides the expanded crate.
+struct Merge {
+    // The path to the item each stub stands for, by the stub's name, and the
+    // items themselves, by their paths in the current step.
+    stubs: HashMap<ast::Name, Vec<usize>>,
+    originals: HashMap<Vec<usize>, P<ast::Item>>,
+    restorer: InvocationRestorer,
+    // The worklist of the current step, and how far the items put back have
+    // taken from it.
+    old: Vec<Pending>,
+    next: usize,
+}
+
+impl Merge {
+    // The pending invocations in the item that was at `from`, which the step
+    // did not change, with the path to it now that it is at `to`. Items are put
+    // back in source order, as the worklist has them.
+    fn carry(&mut self, from: &[usize], to: &[usize]) -> Vec<Pending> {
+        while self.next < self.old.len() && &self.old[self.next].item[..] < from {
+            self.next += 1;
+        }
+        let mut carried = Vec::new();
+        while self.next < self.old.len() && self.old[self.next].item.starts_with(from) {
+            let mut pending = self.old[self.next].clone();
+            pending.item = to.iter().chain(&pending.item[from.len()..]).cloned().collect();
+            carried.push(pending);
+            self.next += 1;
+        }
+        carried
+    }
+}
+
+// Puts back the paths emptied by `InvocationHider` once the step has been expanded.
+struct InvocationRestorer {
+    held: HashMap<(BytePos, BytePos), ast::Path>,
+}
+
+impl Folder for InvocationRestorer {
+    fn fold_mac(&mut self, mac: ast::Mac) -> ast::Mac {
+        if !mac.node.path.segments.is_empty() {
+            return mac;
+        }
+        let path = match self.held.get(&(mac.span.lo, mac.span.hi)) {
+            Some(path) => path.clone(),
+            None => return mac,
+        };
+        Spanned { node: ast::Mac_ { path: path, .. mac.node }, span: mac.span }
+    }
+}
+
+impl<'a> Backend for ExpandData<'a> {
+    type Crate = ast::Crate;
+
+    fn krate(&self) -> &ast::Crate {
+        &self.krate
+    }
+
+    fn filename(&self) -> &str {
+        &self.filename
+    }
+
+    fn index(&self) -> usize {
+        self.index
+    }
+
+    fn events(&self) -> &[Event] {
+        &self.events
+    }
+
+    fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
+    fn pending(&mut self) -> Vec<Invocation> {
+        self.invocations.iter().map(|pending| {
+            Invocation {
+                name: pending.name.clone(),
+                location: self.cx.codemap().span_to_string(pending.span),
+                backtrace: self.backtrace(pending.span),
+            }
+        }).collect()
+    }
+
+    fn expand_all(&mut self) -> Result<HashSet<usize>, String> {
+        self.expand_crate()
+    }
+
+    fn expand_one(&mut self, chosen: usize) -> Result<bool, String> {
+        self.expand_invocation(chosen)
+    }
+
+    // With provenance comments or hygiene the whole crate is printed into the root
+    // file, as rustfmt cannot write them.
+    fn print_step(&self) -> Result<Vec<(String, String)>, String> {
+        if self.provenance || self.hygiene.is_some() {
+            let provenance = ProvenanceAnn::new(&self.events, &self.event_ids);
+            let no_ann = NoAnn;
+            let ann: &PpAnn = if self.provenance { &provenance } else { &no_ann };
+            let marks = MarksAnn::new(ann);
+            let renamed;
+            let (krate, ann): (&ast::Crate, &PpAnn) = match self.hygiene {
+                Some(Hygiene::Marks) => (&self.krate, &marks),
+                Some(Hygiene::Rename) => {
+                    renamed = hygiene::rename(self.krate.clone());
+                    (&renamed, ann)
+                }
+                None => (&self.krate, ann),
+            };
+            let text = try!(self.print_with(krate, ann).map_err(|e| e.to_string()));
+            return Ok(vec![(self.filename.clone(), text)]);
+        }
+        Ok(self.format_step().into_iter().map(|(filename, text)| (filename, text.to_string())).collect())
+    }
+
+    fn print_origins(&self, source: &str, origins: &mut Origins) -> Result<String, String> {
+        let cell = RefCell::new(mem::replace(origins, Origins::default()));
+        let text = {
+            let ann = MarkingAnn::new(|span| {
+                self.mark(span).and_then(|(_, side)| {
+                    annotate::origin(self.cx.codemap(), &self.filename, source, span)
+                        .map(|origin| (cell.borrow_mut().id(origin), side))
+                })
+            });
+            self.print_with(&self.krate, &ann)
+        };
+        *origins = cell.into_inner();
+        text.map_err(|e| e.to_string())
+    }
+
+    fn print_outputs(&self) -> Result<String, String> {
+        let ann = MarkingAnn::new(|span: Span| self.mark(span));
+        self.print_with(&self.krate, &ann).map_err(|e| e.to_string())
+    }
+}
+
+pub type ExpansionStep = session::ExpansionStep;
+
+// The parse session and macro loader that an `ExpansionSession` borrows, which
+// the caller keeps for as long as the session lives.
+pub struct SessionContext {
+    sess: ParseSess,
+    loader: DummyMacroLoader,
+}
+
+impl SessionContext {
+    // A context that reports parse and expansion errors on stderr.
+    pub fn new() -> SessionContext {
+        let codemap = Rc::new(CodeMap::new());
+        let tty_handler = Handler::with_tty_emitter(ColorConfig::Auto,
+                                                    None,
+                                                    true,
+                                                    false,
+                                                    codemap.clone());
+        SessionContext {
+            sess: ParseSess::with_span_handler(tty_handler, codemap),
+            loader: DummyMacroLoader,
+        }
+    }
+}
+
+// The expansion of a crate, one step at a time.
+pub struct ExpansionSession<'a> {
+    steps: Steps<ExpandData<'a>>,
+}
+
+impl<'a> ExpansionSession<'a> {
+    // Parse the crate in `input`, reporting parse errors on stderr. Nothing is
+    // expanded until the first step after step 0 is asked for.
+    pub fn new(context: &'a mut SessionContext,
+               input: Input,
+               options: SessionOptions) -> Result<ExpansionSession<'a>, String> {
+        let data = try!(ExpandData::new(&context.sess,
+                                        &mut context.loader,
+                                        &input,
+                                        options.filter.clone(),
+                                        options.provenance,
+                                        options.hygiene));
+        Ok(ExpansionSession { steps: Steps::new(data, options) })
+    }
+
+    // Why the steps ended before every invocation was expanded, if they did.
+    pub fn error(&self) -> Option<&StepError> {
+        self.steps.error()
+    }
+
+    // Where the code at `line:col` of the module file `file` in the latest
+    // step, as the step's `files` hold it, came from, through every expansion
+    // down to the input.
+    pub fn whence(&self, file: &str, line: usize, col: usize) -> Result<Whence, String> {
+        self.steps.whence(file, line, col)
+    }
+
+    // The crate at the latest step. Earlier steps are kept only as their
+    // printed files.
+    pub fn krate(&self) -> &ast::Crate {
+        self.steps.backend().krate()
+    }
+
+    // The printed files of step `index`, if the session has reached it.
+    pub fn step_files(&self, index: usize) -> Option<Vec<(String, String)>> {
+        self.steps.step_files(index)
+    }
+
+    // What an invocation in the input file expands to in the latest step, with
+    // every expansion nested in it resolved.
+    pub fn outcome(&self, target: Target) -> Result<Outcome, String> {
+        self.steps.outcome(target)
+    }
+}
+
+impl<'a> Iterator for ExpansionSession<'a> {
+    type Item = ExpansionStep;
+
+    fn next(&mut self) -> Option<ExpansionStep> {
+        self.steps.next()
+    }
+}
+
+// Run the command in `options`, as the binary does.
+pub fn run(options: &Options) -> Result<(), String> {
+    let input = Input::File(options.filename.clone().into());
+    let mut context = SessionContext::new();
+    let mut session = try!(ExpansionSession::new(&mut context, input, options.session_options()));
+    engine::run(options, &mut session.steps)
+}
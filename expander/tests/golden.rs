@@ -0,0 +1,31 @@
+// Golden-file tests of expander over the fixtures in the repository's `tests/`
+// directory, against the files under `tests/expected/expander/`. They hold for
+// the nightly they were blessed with only, so the harness is built with the
+// `golden` feature: `cargo test --features golden --test golden`, and
+// `-- --bless` writes the expected files from the current output.
+
+#![allow(deprecated)]
+
+extern crate macro_expander_core;
+extern crate expander;
+
+use macro_expander_core::golden;
+use macro_expander_core::session::{Input, SessionOptions};
+
+use expander::{ExpansionSession, SessionContext};
+
+use std::path::Path;
+
+fn main() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("../tests");
+    golden::main(&dir, "expander", |fixture| {
+        let input = Input::File(fixture.to_path_buf());
+        let mut context = SessionContext::new();
+        let mut session = try!(ExpansionSession::new(&mut context, input, SessionOptions::default()));
+        let steps: Vec<Vec<(String, String)>> = session.by_ref().map(|step| step.files).collect();
+        match session.error() {
+            Some(error) => Err(error.report(&format!("step {}", steps.len().saturating_sub(1)))),
+            None => Ok(steps),
+        }
+    });
+}
@@ -0,0 +1,70 @@
+// The files a crate's modules are in: the root file, and those of the modules
+// declared with `mod foo;` (following `#[path]`), found through the codemap as
+// `rustfmt::modules` does for the `expander` crate.
+
+use syntax::ast;
+use syntax::codemap::{CodeMap, NO_EXPANSION};
+
+use std::path::PathBuf;
+
+// A module file, with the attributes whose inner ones are written at its top.
+pub struct ModuleFile<'a> {
+    pub path: PathBuf,
+    pub module: &'a ast::Mod,
+    pub attrs: &'a [ast::Attribute],
+}
+
+// Whether `item` is a module with a file of its own, rather than one written
+// inline in `file`. Modules that came from a macro are always printed inline.
+pub fn is_external(codemap: &CodeMap, file: &str, item: &ast::Item) -> bool {
+    match item.node {
+        ast::ItemKind::Mod(ref module) => {
+            item.span.expn_id == NO_EXPANSION && codemap.span_to_filename(module.inner) != file
+        }
+        _ => false,
+    }
+}
+
+// Every module file of `krate`, whose root file is `root`, root first.
+pub fn list_files<'a>(krate: &'a ast::Crate, root: &str, codemap: &CodeMap) -> Vec<ModuleFile<'a>> {
+    let mut files = vec![ModuleFile {
+        path: PathBuf::from(root),
+        module: &krate.module,
+        attrs: &krate.attrs,
+    }];
+    list_submodules(&krate.module, root, codemap, &mut files);
+    files
+}
+
+// Whether the inline module `module`, written in `file`, declares a module with
+// a file of its own at any depth, so that it cannot be printed whole.
+pub fn declares_files(codemap: &CodeMap, file: &str, module: &ast::Mod) -> bool {
+    module.items.iter().any(|item| match item.node {
+        ast::ItemKind::Mod(ref sub) => {
+            is_external(codemap, file, item) || declares_files(codemap, file, sub)
+        }
+        _ => false,
+    })
+}
+
+// Inline modules are printed with the file they are in, but the modules
+// declared in them have files of their own, as `a/b.rs` for `mod a { mod b; }`,
+// so they are followed too. The codemap has each file where the compiler found it.
+fn list_submodules<'a>(module: &'a ast::Mod,
+                       file: &str,
+                       codemap: &CodeMap,
+                       files: &mut Vec<ModuleFile<'a>>) {
+    for item in &module.items {
+        let sub = match item.node {
+            ast::ItemKind::Mod(ref sub) => sub,
+            _ => continue,
+        };
+        if !is_external(codemap, file, item) {
+            list_submodules(sub, file, codemap, files);
+            continue;
+        }
+        let path = codemap.span_to_filename(sub.inner);
+        files.push(ModuleFile { path: PathBuf::from(&path), module: sub, attrs: &item.attrs });
+        list_submodules(sub, &path, codemap, files);
+    }
+}
@@ -0,0 +1,290 @@
+// Span fixing, checked on the last step of a crate whose code comes from
+// nested expansions in different steps: every node that came from a macro must
+// have a backtrace through each invocation that produced it. Also, an invocation
+// the expander gives up on is left as it is while the rest is expanded, and
+// `--hygiene rename` renames variables but not the fields that share their name.
+// Invocations after an item macro are found where its output moved them to.
+
+use syntax::ast;
+use syntax::codemap::Span;
+use syntax::visit::{self, Visitor};
+
+use macro_expander_core::cli::Hygiene;
+use macro_expander_core::engine::Backend;
+use macro_expander_core::session::{Input, SessionOptions};
+
+use super::{ExpansionSession, SessionContext};
+
+use std::collections::HashSet;
+
+const SOURCE: &'static str = "\
+macro_rules! inner {
+    ($e:expr) => { { let copy = $e; copy } }
+}
+macro_rules! outer {
+    () => {
+        #[allow(dead_code)]
+        struct S<T: Clone> where T: Copy {
+            field: T,
+        }
+        impl<T: Clone + Copy> S<T> {
+            fn get(&self) -> T { self.field }
+        }
+        trait Tr { fn tr(&self) -> u32; }
+        fn f<T>(s: S<T>) -> T where T: Clone + Copy {
+            let local: T = s.get();
+            match local { x => inner!(x) }
+        }
+    }
+}
+outer!();
+fn main() {}
+";
+
+// The lines of `outer!`'s body, and where it is invoked.
+const OUTER_BODY: (usize, usize) = (6, 17);
+const OUTER_CALL: &'static str = "test.rs:20:1";
+
+// The kinds of node in `outer!`'s body, each of which must be fixed.
+const KINDS: &'static [&'static str] = &[
+    "item", "attribute", "struct field", "type parameter", "where predicate", "impl item",
+    "trait item", "block", "statement", "local", "expression", "pattern", "type", "path",
+];
+
+// Every span in the crate, by the kind of node it is the span of.
+struct SpanCollector {
+    spans: Vec<(&'static str, Span)>,
+}
+
+impl<'v> Visitor<'v> for SpanCollector {
+    fn visit_item(&mut self, item: &'v ast::Item) {
+        self.spans.push(("item", item.span));
+        for attr in &item.attrs {
+            self.spans.push(("attribute", attr.span));
+        }
+        visit::walk_item(self, item)
+    }
+
+    fn visit_struct_field(&mut self, field: &'v ast::StructField) {
+        self.spans.push(("struct field", field.span));
+        visit::walk_struct_field(self, field)
+    }
+
+    fn visit_generics(&mut self, generics: &'v ast::Generics) {
+        for param in generics.ty_params.iter() {
+            self.spans.push(("type parameter", param.span));
+        }
+        for predicate in &generics.where_clause.predicates {
+            if let ast::WherePredicate::BoundPredicate(ref bound) = *predicate {
+                self.spans.push(("where predicate", bound.span));
+            }
+        }
+        visit::walk_generics(self, generics)
+    }
+
+    fn visit_impl_item(&mut self, item: &'v ast::ImplItem) {
+        self.spans.push(("impl item", item.span));
+        visit::walk_impl_item(self, item)
+    }
+
+    fn visit_trait_item(&mut self, item: &'v ast::TraitItem) {
+        self.spans.push(("trait item", item.span));
+        visit::walk_trait_item(self, item)
+    }
+
+    fn visit_block(&mut self, block: &'v ast::Block) {
+        self.spans.push(("block", block.span));
+        visit::walk_block(self, block)
+    }
+
+    fn visit_stmt(&mut self, stmt: &'v ast::Stmt) {
+        self.spans.push(("statement", stmt.span));
+        visit::walk_stmt(self, stmt)
+    }
+
+    fn visit_local(&mut self, local: &'v ast::Local) {
+        self.spans.push(("local", local.span));
+        visit::walk_local(self, local)
+    }
+
+    fn visit_expr(&mut self, expr: &'v ast::Expr) {
+        self.spans.push(("expression", expr.span));
+        visit::walk_expr(self, expr)
+    }
+
+    fn visit_pat(&mut self, pat: &'v ast::Pat) {
+        self.spans.push(("pattern", pat.span));
+        visit::walk_pat(self, pat)
+    }
+
+    fn visit_ty(&mut self, ty: &'v ast::Ty) {
+        self.spans.push(("type", ty.span));
+        visit::walk_ty(self, ty)
+    }
+
+    fn visit_path(&mut self, path: &'v ast::Path, _id: ast::NodeId) {
+        self.spans.push(("path", path.span));
+        visit::walk_path(self, path)
+    }
+
+    fn visit_mac(&mut self, _mac: &'v ast::Mac) {}
+}
+
+// The session for `SOURCE`, stepped through to the end.
+fn expand(context: &mut SessionContext) -> ExpansionSession {
+    let input = Input::Source { name: "test.rs".to_owned(), text: SOURCE.to_owned() };
+    let mut session = ExpansionSession::new(context, input, SessionOptions::default()).unwrap();
+    while let Some(_) = session.next() {}
+    assert!(session.error().is_none());
+    session
+}
+
+#[test]
+fn every_node_kind_from_a_macro_has_its_backtrace() {
+    let mut context = SessionContext::new();
+    let session = expand(&mut context);
+    let data = session.steps.backend();
+    let mut collector = SpanCollector { spans: Vec::new() };
+    visit::walk_crate(&mut collector, data.krate());
+
+    let mut fixed = HashSet::new();
+    for &(kind, span) in &collector.spans {
+        let line = data.cx.codemap().lookup_char_pos(span.lo).line;
+        if line < OUTER_BODY.0 || line > OUTER_BODY.1 {
+            continue;
+        }
+        let frames = data.backtrace(span);
+        let outermost = frames.last().unwrap_or_else(|| {
+            panic!("{} at line {} has no backtrace", kind, line)
+        });
+        assert_eq!(outermost.name, "outer!", "{} at line {}", kind, line);
+        assert!(outermost.call_site.starts_with(OUTER_CALL),
+                "{} at line {} was called at {}", kind, line, outermost.call_site);
+        fixed.insert(kind);
+    }
+    for kind in KINDS {
+        assert!(fixed.contains(kind), "no {} from outer! was found", kind);
+    }
+}
+
+#[test]
+fn nodes_from_a_nested_expansion_chain_through_both_call_sites() {
+    let mut context = SessionContext::new();
+    let session = expand(&mut context);
+    let data = session.steps.backend();
+    let mut collector = SpanCollector { spans: Vec::new() };
+    visit::walk_crate(&mut collector, data.krate());
+
+    let nested: Vec<&str> = collector.spans.iter().filter_map(|&(kind, span)| {
+        let names: Vec<String> = data.backtrace(span).into_iter().map(|frame| frame.name).collect();
+        if names == ["inner!", "outer!"] { Some(kind) } else { None }
+    }).collect();
+    for kind in &["block", "local", "pattern", "expression", "path"] {
+        assert!(nested.contains(kind), "no {} from inner! within outer! was found", kind);
+    }
+}
+
+#[test]
+fn an_invocation_the_expander_gives_up_on_is_left_with_a_diagnostic() {
+    let text = "\
+macro_rules! pair {
+    ($a:expr, $b:expr) => { ($a, $b) }
+}
+fn main() {
+    let good = pair!(1, 2);
+    let bad = pair!(1 2);
+}
+";
+    let input = Input::Source { name: "test.rs".to_owned(), text: text.to_owned() };
+    let mut context = SessionContext::new();
+    let mut session = ExpansionSession::new(&mut context, input, SessionOptions::default()).unwrap();
+    let steps: Vec<_> = session.by_ref().collect();
+    assert!(session.error().is_none());
+
+    let last = steps.last().unwrap();
+    assert!(last.pending.is_empty());
+    let printed = &last.files[0].1;
+    assert!(printed.contains("let good = (1, 2);"), "{}", printed);
+    assert!(printed.lines().any(|line| line.contains("let bad = pair!")), "{}", printed);
+
+    let diagnostics: Vec<_> = steps.iter().flat_map(|step| step.diagnostics.iter()).collect();
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].name, "pair!");
+    assert_eq!((diagnostics[0].span.lo.line, diagnostics[0].step), (6, 1));
+    assert_eq!(steps[1].expanded.len(), 1);
+}
+
+#[test]
+fn renaming_a_binding_leaves_fields_of_the_same_name() {
+    let text = "\
+struct S { x: i32 }
+macro_rules! get {
+    () => { { let x = S { x: 1 }; x.x } }
+}
+fn main() {
+    let y = get!();
+}
+";
+    let input = Input::Source { name: "test.rs".to_owned(), text: text.to_owned() };
+    let options = SessionOptions { hygiene: Some(Hygiene::Rename), .. SessionOptions::default() };
+    let mut context = SessionContext::new();
+    let session = ExpansionSession::new(&mut context, input, options).unwrap();
+    let last = session.last().unwrap();
+    let printed = &last.files[0].1;
+    assert!(printed.contains("let x_"), "{}", printed);
+    assert!(printed.contains(".x"), "{}", printed);
+    assert!(!printed.contains(".x_") && !printed.contains("{x_"), "{}", printed);
+}
+
+#[test]
+fn a_renamed_binding_does_not_take_a_name_the_user_wrote() {
+    let mut text = "macro_rules! m {\n    ($e:expr) => { { let x = 2; $e + x } }\n}\nfn main() {\n".to_owned();
+    // The name the macro's `x` would first be renamed to, `x_` and its context,
+    // is taken, for any context below 10.
+    for ctxt in 0..10 {
+        text.push_str(&format!("    let x_{} = 1;\n", ctxt));
+    }
+    text.push_str("    let x = 1;\n    let y = m!(x + x_1);\n}\n");
+    let input = Input::Source { name: "test.rs".to_owned(), text: text };
+    let options = SessionOptions { hygiene: Some(Hygiene::Rename), .. SessionOptions::default() };
+    let mut context = SessionContext::new();
+    let session = ExpansionSession::new(&mut context, input, options).unwrap();
+    let last = session.last().unwrap();
+    let printed = &last.files[0].1;
+    let tail = printed.split("x + x_1").nth(1).unwrap_or_else(|| panic!("{}", printed));
+    let name: String = tail.trim_left_matches(')').trim_left_matches(" + ")
+                           .chars().take_while(|&c| c == '_' || c.is_alphanumeric()).collect();
+    assert!(name.starts_with("x_"), "{}", printed);
+    assert!(!printed.contains(&format!("let {} = 1;", name)), "{}", printed);
+    assert!(printed.contains(&format!("let {} = 2;", name)), "{}", printed);
+}
+
+#[test]
+fn invocations_after_an_item_macro_are_expanded_where_they_moved_to() {
+    let text = "\
+macro_rules! two {
+    () => { fn a() {} fn b() {} }
+}
+macro_rules! one {
+    () => { 1 }
+}
+two!();
+mod m {
+    fn f() -> u32 { one!() }
+}
+fn g() -> u32 { one!() + 1 }
+";
+    let input = Input::Source { name: "test.rs".to_owned(), text: text.to_owned() };
+    let options = SessionOptions { one_by_one: true, .. SessionOptions::default() };
+    let mut context = SessionContext::new();
+    let mut session = ExpansionSession::new(&mut context, input, options).unwrap();
+    let steps: Vec<_> = session.by_ref().collect();
+    assert!(session.error().is_none());
+
+    let last = steps.last().unwrap();
+    assert!(last.pending.is_empty());
+    let printed = &last.files[0].1;
+    assert!(printed.contains("fn a() {}") && printed.contains("fn b() {}"), "{}", printed);
+    assert!(!printed.contains("one!()"), "{}", printed);
+    assert!(printed.contains("1 + 1"), "{}", printed);
+}
@@ -0,0 +1,124 @@
+// Printer annotations for code that came from a macro expansion: markers
+// around it for the HTML and JSON traces, and provenance comments before it.
+
+use macro_expander_core::events::{Event, Position};
+use macro_expander_core::html::Origin;
+use macro_expander_core::markers::{self, Side, CLOSE};
+
+use printer::Ann;
+use tokens::Span;
+
+use std::cell::RefCell;
+
+// Marks every run of tokens for which `mark_of` gives the same id and side of
+// the invocation they were written on.
+pub struct MarkingAnn<F> {
+    mark_of: F,
+    // Every mark given so far; a run's id for the printer is its index here.
+    marks: RefCell<Vec<(usize, Side)>>,
+}
+
+impl<F: Fn(&Span) -> Option<(usize, Side)>> MarkingAnn<F> {
+    pub fn new(mark_of: F) -> MarkingAnn<F> {
+        MarkingAnn { mark_of: mark_of, marks: RefCell::new(Vec::new()) }
+    }
+}
+
+impl<F: Fn(&Span) -> Option<(usize, Side)>> Ann for MarkingAnn<F> {
+    fn id(&self, span: &Span) -> Option<usize> {
+        let mark = try_opt!((self.mark_of)(span));
+        let mut marks = self.marks.borrow_mut();
+        match marks.iter().position(|&known| known == mark) {
+            Some(i) => Some(i),
+            None => {
+                marks.push(mark);
+                Some(marks.len() - 1)
+            }
+        }
+    }
+
+    fn open(&self, i: usize) -> String {
+        let (id, side) = self.marks.borrow()[i];
+        markers::open(id, side)
+    }
+
+    fn close(&self, _id: usize) -> String {
+        CLOSE.to_string()
+    }
+}
+
+// Writes a comment naming the invocation that produced each run of tokens
+// that came from a macro, before it.
+pub struct ProvenanceAnn<'a> {
+    events: &'a [Event],
+}
+
+impl<'a> ProvenanceAnn<'a> {
+    pub fn new(events: &'a [Event]) -> ProvenanceAnn<'a> {
+        ProvenanceAnn { events: events }
+    }
+}
+
+impl<'a> Ann for ProvenanceAnn<'a> {
+    fn id(&self, span: &Span) -> Option<usize> {
+        span.expn
+    }
+
+    fn open(&self, id: usize) -> String {
+        let event = &self.events[id];
+        format!("expanded from {} at {}:{}:{} (step {})",
+                event.name,
+                event.call_site.file,
+                event.call_site.lo.line,
+                event.call_site.lo.col,
+                event.step)
+    }
+
+    fn comments(&self) -> bool {
+        true
+    }
+}
+
+// Follow the call sites of `span` back to the invocation in `filename`, whose
+// contents are `source`.
+pub fn origin(events: &[Event], filename: &str, source: &str, span: &Span) -> Option<Origin> {
+    let mut id = try_opt!(span.expn);
+    let mut chain = Vec::new();
+    loop {
+        chain.insert(0, events[id].name.clone());
+        match events[id].parent {
+            Some(parent) => id = parent,
+            None => break,
+        }
+    }
+
+    let call_site = &events[id].call_site;
+    if call_site.file != filename {
+        return None;
+    }
+    let lo = try_opt!(char_offset(source, &call_site.lo));
+    let hi = try_opt!(char_offset(source, &call_site.hi));
+    Some(Origin {
+        chain: chain,
+        location: format!("{}:{}", call_site.lo.line, call_site.lo.col),
+        lo: lo,
+        hi: hi,
+    })
+}
+
+// The character offset in `source` of `pos`, whose column counts bytes.
+fn char_offset(source: &str, pos: &Position) -> Option<usize> {
+    let mut start = 0;
+    for (i, line) in source.split('\n').enumerate() {
+        if i + 1 == pos.line {
+            let byte = start + pos.col - 1;
+            return if pos.col - 1 <= line.len() && source.is_char_boundary(byte) {
+                Some(source[..byte].chars().count())
+            } else {
+                None
+            };
+        }
+        start += line.len() + 1;
+    }
+    None
+}
@@ -0,0 +1,254 @@
+// Hygiene for the stable backend. Every token a macro's body produces is marked
+// with the expansion, as the compiler's expander marks the syntax context of
+// its identifiers, so that a variable a macro introduces can be told apart from
+// the user's variable of the same name. Tokens substituted from the arguments
+// of an invocation keep the context they had.
+
+use tokens::{Delim, Token, TokenKind};
+
+use std::collections::{HashMap, HashSet};
+
+// The syntax contexts made so far, each the context a token had before an
+// expansion marked it and that expansion. Context 0 is the input's.
+#[derive(Default)]
+pub struct Contexts {
+    ids: HashMap<(usize, usize), usize>,
+}
+
+impl Contexts {
+    // Mark every token in `tokens` with the expansion `expn`.
+    pub fn mark(&mut self, tokens: &mut [Token], expn: usize) {
+        for token in tokens {
+            let next = self.ids.len() + 1;
+            token.span.ctxt = *self.ids.entry((token.span.ctxt, expn)).or_insert(next);
+            if let TokenKind::Group(_, ref mut inner) = token.kind {
+                self.mark(inner, expn);
+            }
+        }
+    }
+}
+
+// Words that are not identifiers in the compiler's AST, and so have no context.
+const KEYWORDS: &'static [&'static str] = &[
+    "_", "as", "box", "break", "const", "continue", "crate", "dyn", "else", "enum", "extern",
+    "false", "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut", "pub",
+    "ref", "return", "self", "Self", "static", "struct", "super", "trait", "true", "type",
+    "unsafe", "use", "where", "while",
+];
+
+fn has_context(ident: &str) -> bool {
+    !KEYWORDS.contains(&ident)
+}
+
+// `tokens` with every identifier a macro introduced followed by its context, as
+// in `x#3`.
+pub fn show_marks(tokens: &[Token]) -> Vec<Token> {
+    tokens.iter().map(|token| {
+        let kind = match token.kind {
+            TokenKind::Ident(ref ident) if token.span.ctxt != 0 && has_context(ident) => {
+                TokenKind::Ident(format!("{}#{}", ident, token.span.ctxt))
+            }
+            TokenKind::Group(delim, ref inner) => TokenKind::Group(delim, show_marks(inner)),
+            ref kind => kind.clone(),
+        };
+        Token { kind: kind, span: token.span.clone() }
+    }).collect()
+}
+
+// `tokens` with the local variables that macros introduced renamed apart, as
+// in `x_3`, so that none captures, or is captured by, another of the same name.
+pub fn rename(tokens: &[Token]) -> Vec<Token> {
+    let mut locals = HashSet::new();
+    find_locals(tokens, &mut locals);
+    let mut names = HashSet::new();
+    find_names(tokens, &mut names);
+    rename_locals(tokens, &fresh_names(locals, &mut names))
+}
+
+// A new name for each local, `name_ctxt`, or `name_ctxt_N` with the first `N`
+// that makes it unlike every name in `names`, which it is added to.
+fn fresh_names(locals: HashSet<(String, usize)>,
+               names: &mut HashSet<String>) -> HashMap<(String, usize), String> {
+    let mut locals: Vec<(String, usize)> = locals.into_iter().collect();
+    locals.sort();
+    locals.into_iter().map(|local| {
+        let base = format!("{}_{}", local.0, local.1);
+        let mut name = base.clone();
+        let mut n = 0;
+        while names.contains(&name) {
+            n += 1;
+            name = format!("{}_{}", base, n);
+        }
+        names.insert(name.clone());
+        (local, name)
+    }).collect()
+}
+
+// Gather every identifier in `tokens`.
+fn find_names(tokens: &[Token], names: &mut HashSet<String>) {
+    for token in tokens {
+        match token.kind {
+            TokenKind::Ident(ref ident) => {
+                names.insert(ident.clone());
+            }
+            TokenKind::Group(_, ref inner) => find_names(inner, names),
+            _ => {}
+        }
+    }
+}
+
+fn rename_locals(tokens: &[Token], renamed: &HashMap<(String, usize), String>) -> Vec<Token> {
+    tokens.iter().enumerate().map(|(i, token)| {
+        let kind = match token.kind {
+            TokenKind::Ident(ref ident) if !is_member(tokens, i) => {
+                match renamed.get(&(ident.clone(), token.span.ctxt)) {
+                    Some(name) => TokenKind::Ident(name.clone()),
+                    None => TokenKind::Ident(ident.clone()),
+                }
+            }
+            TokenKind::Group(delim, ref inner) => TokenKind::Group(delim, rename_locals(inner, renamed)),
+            ref kind => kind.clone(),
+        };
+        Token { kind: kind, span: token.span.clone() }
+    }).collect()
+}
+
+fn is_path_sep(tokens: &[Token], i: usize) -> bool {
+    tokens.get(i).map_or(false, |t| t.is_punct(':')) &&
+    tokens.get(i + 1).map_or(false, |t| t.is_punct(':'))
+}
+
+// Whether the identifier at `i` is a field, a method or a path segment, rather
+// than a variable.
+fn is_member(tokens: &[Token], i: usize) -> bool {
+    (i >= 1 && tokens[i - 1].is_punct('.')) || (i >= 2 && is_path_sep(tokens, i - 2)) ||
+    is_path_sep(tokens, i + 1)
+}
+
+// Gather the variables a macro bound in `tokens`: the identifiers with a
+// context in the patterns of `let`, `for`, match arms, closure parameters and
+// function parameters.
+fn find_locals(tokens: &[Token], locals: &mut HashSet<(String, usize)>) {
+    let mut i = 0;
+    while i < tokens.len() {
+        let token = &tokens[i];
+        if token.is_ident("let") {
+            let end = pattern_end(tokens, i + 1, |t| t.is_punct('=') || t.is_punct(';'));
+            bind(&tokens[i + 1..end], locals);
+        } else if token.is_ident("for") {
+            let end = pattern_end(tokens, i + 1, |t| t.is_ident("in"));
+            bind(&tokens[i + 1..end], locals);
+        } else if token.is_ident("match") {
+            let body = tokens[i + 1..].iter().filter_map(|t| t.group())
+                                      .find(|&(delim, _)| delim == Delim::Brace);
+            if let Some((_, arms)) = body {
+                bind_arms(arms, locals);
+            }
+        } else if token.is_ident("fn") {
+            let params = tokens[i + 1..].iter().filter_map(|t| t.group())
+                                        .find(|&(delim, _)| delim == Delim::Paren);
+            if let Some((_, params)) = params {
+                bind_params(params, locals);
+            }
+        } else if token.is_punct('|') && starts_closure(tokens, i) {
+            let end = pattern_end(tokens, i + 1, |t| t.is_punct('|'));
+            bind_params(&tokens[i + 1..end], locals);
+            i = end;
+        }
+        if let TokenKind::Group(_, ref inner) = token.kind {
+            find_locals(inner, locals);
+        }
+        i += 1;
+    }
+}
+
+// Where the pattern starting at `start` ends: at the first token from there
+// for which `stop` holds, or at a type ascription's `:`.
+fn pattern_end<F: Fn(&Token) -> bool>(tokens: &[Token], start: usize, stop: F) -> usize {
+    let mut i = start;
+    while i < tokens.len() {
+        if is_path_sep(tokens, i) {
+            i += 2;
+            continue;
+        }
+        if stop(&tokens[i]) || tokens[i].is_punct(':') {
+            return i;
+        }
+        i += 1;
+    }
+    tokens.len()
+}
+
+// Whether the `|` at `i` opens the parameters of a closure, being where an
+// expression starts, rather than an `or`.
+fn starts_closure(tokens: &[Token], i: usize) -> bool {
+    if i == 0 {
+        return true;
+    }
+    let prev = &tokens[i - 1];
+    ['=', ',', ';', '(', '>'].iter().any(|&c| prev.is_punct(c)) ||
+    prev.is_ident("move") || prev.is_ident("return")
+}
+
+// Comma-separated parameters, each a pattern with an optional type.
+fn bind_params(params: &[Token], locals: &mut HashSet<(String, usize)>) {
+    for param in params.split(|t| t.is_punct(',')) {
+        let end = pattern_end(param, 0, |_| false);
+        bind(&param[..end], locals);
+    }
+}
+
+// The arms of a match: each pattern runs up to its guard or `=>`.
+fn bind_arms(arms: &[Token], locals: &mut HashSet<(String, usize)>) {
+    let mut start = 0;
+    while start < arms.len() {
+        let mut end = start;
+        while end < arms.len() && !arms[end].is_ident("if") &&
+              !(arms[end].is_punct('=') && arms.get(end + 1).map_or(false, |t| t.is_punct('>'))) {
+            end += 1;
+        }
+        bind(&arms[start..end], locals);
+        // Skip the arm's body: a block, or an expression up to its comma.
+        let mut next = end;
+        while next < arms.len() &&
+              !(arms[next].is_punct('=') && arms.get(next + 1).map_or(false, |t| t.is_punct('>'))) {
+            next += 1;
+        }
+        next += 2;
+        let block = arms.get(next).and_then(|t| t.group()).map_or(false, |(d, _)| d == Delim::Brace);
+        if block {
+            next += 1;
+        } else {
+            while next < arms.len() && !arms[next].is_punct(',') {
+                next += 1;
+            }
+        }
+        if arms.get(next).map_or(false, |t| t.is_punct(',')) {
+            next += 1;
+        }
+        start = next;
+    }
+}
+
+// The variables a pattern binds: identifiers other than keywords that do not
+// start a path, call or struct pattern, and are not field names. Those that
+// start with a capital letter are taken for unit structs and variants.
+fn bind(pattern: &[Token], locals: &mut HashSet<(String, usize)>) {
+    for (i, token) in pattern.iter().enumerate() {
+        match token.kind {
+            TokenKind::Ident(ref ident) => {
+                let next = pattern.get(i + 1);
+                let binds = token.span.ctxt != 0 && has_context(ident) &&
+                            !ident.starts_with(|c: char| c.is_uppercase()) &&
+                            !next.map_or(false, |t| t.is_punct(':') || t.is_punct('!') ||
+                                                     t.group().map_or(false, |(d, _)| d != Delim::Bracket)) &&
+                            !(i >= 2 && is_path_sep(pattern, i - 2));
+                if binds {
+                    locals.insert((ident.clone(), token.span.ctxt));
+                }
+            }
+            TokenKind::Group(_, ref inner) => bind(inner, locals),
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,499 @@
+// The stable frontend: steps through the expansion of a crate without the
+// compiler's expander. The crate is parsed with syn and worked on as token
+// trees, and `macro_rules!` invocations are matched and transcribed here, so it
+// builds on a stable toolchain. `ExpansionSession` is the library entry point,
+// and `run` the one the `stable-expander` binary uses.
+
+// Written for the 2015 edition, as `macro_expander_core` is, whose lints against
+// the idioms of the time it leaves alone likewise.
+#![allow(deprecated, bare_trait_objects)]
+#![allow(clippy::get_first,
+         clippy::match_like_matches_macro,
+         clippy::mem_replace_with_default,
+         clippy::needless_borrowed_reference,
+         clippy::redundant_closure,
+         clippy::redundant_field_names,
+         clippy::redundant_static_lifetimes,
+         clippy::result_large_err,
+         clippy::unnecessary_map_or)]
+
+extern crate macro_expander_core;
+extern crate proc_macro2;
+#[macro_use]
+extern crate syn;
+
+// `try!` for options.
+macro_rules! try_opt {
+    ($e:expr) => {
+        match $e {
+            Some(value) => value,
+            None => return None,
+        }
+    }
+}
+
+mod annotate;
+mod hygiene;
+mod mbe;
+mod printer;
+pub mod tokens;
+mod walk;
+#[cfg(test)]
+mod tests;
+
+use macro_expander_core::backtrace::Frame;
+use macro_expander_core::cli::{Hygiene, MacroFilter, Options};
+use macro_expander_core::engine::{self, Backend, Invocation};
+use macro_expander_core::events::{self, ArmFailure, Diagnostic, Event, Position, RuleMatch, SpanInfo};
+use macro_expander_core::html::Origins;
+use macro_expander_core::markers::Side;
+use macro_expander_core::outcome::{Outcome, Target};
+use macro_expander_core::session::{self, Input, SessionOptions, StepError, Steps};
+use macro_expander_core::whence::Whence;
+
+use annotate::{MarkingAnn, ProvenanceAnn};
+use hygiene::Contexts;
+use mbe::{Binding, Bindings, Failure, Match, MacroDef};
+use printer::NoAnn;
+use tokens::{Span, Token};
+use walk::{Site, Walk};
+
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::Read;
+use std::mem;
+
+struct ExpandData {
+    filename: String,
+    // The token trees at the current step. Earlier steps are kept by `Steps`
+    // only as printed text.
+    krate: Vec<Token>,
+    // Invocations of macros the filter excludes are never expanded.
+    filter: MacroFilter,
+    // Whether printed steps comment expanded code with where it came from.
+    provenance: bool,
+    // How printed steps show the syntax contexts of identifiers, if at all.
+    hygiene: Option<Hygiene>,
+    index: usize,
+    // Every expansion so far; the `expn` of a token is its index here.
+    events: Vec<Event>,
+    // The `macro_rules!` definitions in the crate, and the worklist: the
+    // invocations pending in the current step, in source order. Each step
+    // replaces the invocations it expands with those in their outputs.
+    definitions: Vec<MacroDef>,
+    sites: Vec<Site>,
+    // The arm each pending invocation matched, its bindings and how it
+    // matched, as `sites` has them.
+    matched: Vec<(usize, Bindings, RuleMatch)>,
+    contexts: Contexts,
+    // The invocations and definitions that could not be expanded, each
+    // reported once, in the first step it is found in.
+    diagnostics: Vec<Diagnostic>,
+    reported: HashSet<(String, Option<usize>)>,
+}
+
+// A span as `file:line:col: line:col`, as libsyntax's codemap writes them.
+fn span_to_string(span: &SpanInfo) -> String {
+    format!("{}:{}:{}: {}:{}", span.file, span.lo.line, span.lo.col, span.hi.line, span.hi.col)
+}
+
+fn span_info(span: &Span) -> SpanInfo {
+    SpanInfo {
+        file: (*span.file).clone(),
+        lo: Position { line: span.lo.line, col: span.lo.col + 1 },
+        hi: Position { line: span.hi.line, col: span.hi.col + 1 },
+    }
+}
+
+// Tokens printed on one line, for reports.
+fn inline(tokens: &[Token]) -> String {
+    let text = printer::print(tokens, &NoAnn);
+    let lines: Vec<&str> = text.lines().map(|line| line.trim()).collect();
+    lines.join(" ")
+}
+
+// The tokens a metavariable matched, with a list for each repetition.
+fn binding_value(binding: &Binding) -> String {
+    match *binding {
+        Binding::One(ref tokens, _) => inline(tokens),
+        Binding::Seq(ref seq) => {
+            let values: Vec<String> = seq.iter().map(binding_value).collect();
+            format!("[{}]", values.join(", "))
+        }
+    }
+}
+
+fn arm_failure(def: &MacroDef, arm: usize, failure: &Failure) -> ArmFailure {
+    ArmFailure {
+        arm: arm,
+        matcher: inline(&[def.arms[arm].lhs.clone()]),
+        found: failure.found(),
+        expected: failure.expected.clone(),
+        at: failure.token.as_ref().map(|token| span_info(&token.span)),
+    }
+}
+
+// How an invocation matched the `arm`th arm of `def`, the arms before it
+// having failed as in `failures`.
+fn rule_match(def: &MacroDef, arm: usize, bindings: &Bindings, failures: &[Failure]) -> RuleMatch {
+    let mut vars = Vec::new();
+    mbe::var_names(&def.arms[arm].matcher, &mut vars);
+    RuleMatch {
+        arm: arm,
+        matcher: inline(&[def.arms[arm].lhs.clone()]),
+        bindings: vars.into_iter().map(|(name, kind)| {
+            events::Binding {
+                value: bindings.get(&name).map_or(String::new(), binding_value),
+                name: name,
+                fragment: kind.name().to_owned(),
+            }
+        }).collect(),
+        failed: failures.iter().enumerate().map(|(i, failure)| arm_failure(def, i, failure)).collect(),
+    }
+}
+
+// The arm of `def` that `site` matches, its bindings and how it matched, or
+// why it cannot be expanded and the arm that came closest to matching.
+fn expand_site(site: &Site, def: &MacroDef)
+               -> Result<(usize, Bindings, RuleMatch), (String, Option<ArmFailure>)> {
+    let (arm, bindings, failures) = match Match::new(def).find_arm(&site.args) {
+        Ok(found) => found,
+        Err(failures) => {
+            let closest = mbe::closest(&failures);
+            let failure = arm_failure(def, closest, &failures[closest]);
+            return Err((format!("no rules expected {}", failure.found), Some(failure)));
+        }
+    };
+    try!(mbe::transcribe(&def.arms[arm].transcriber, &bindings).map_err(|msg| (msg, None)));
+    let rule = rule_match(def, arm, &bindings, &failures);
+    Ok((arm, bindings, rule))
+}
+
+impl ExpandData {
+    fn new(input: &Input, filter: MacroFilter, provenance: bool, hygiene: Option<Hygiene>)
+           -> Result<ExpandData, String> {
+        let filename = input.name();
+        let source = match *input {
+            Input::File(ref path) => {
+                let mut source = String::new();
+                try!(File::open(path).and_then(|mut f| f.read_to_string(&mut source))
+                     .map_err(|e| format!("could not read {}: {}", filename, e)));
+                source
+            }
+            Input::Source { ref text, .. } => text.clone(),
+        };
+        try!(syn::parse_file(&source).map_err(|e| {
+            let start = e.span().start();
+            format!("could not parse {}:{}:{}: {}", filename, start.line, start.column + 1, e)
+        }));
+        let krate = try!(tokens::tokenize(&filename, &source));
+        let mut data = ExpandData {
+            filename: filename,
+            krate: krate,
+            filter: filter,
+            provenance: provenance,
+            hygiene: hygiene,
+            index: 0,
+            events: Vec::new(),
+            definitions: Vec::new(),
+            sites: Vec::new(),
+            matched: Vec::new(),
+            contexts: Contexts::default(),
+            diagnostics: Vec::new(),
+            reported: HashSet::new(),
+        };
+        data.find_sites();
+        Ok(data)
+    }
+
+    // Walk the whole crate for its definitions and invocations, and start the
+    // worklist afresh with those to expand.
+    fn find_sites(&mut self) {
+        let mut walk = Walk::new();
+        walk.walk_crate(&self.krate);
+        for (name, span, msg) in mem::replace(&mut walk.errors, Vec::new()) {
+            self.diagnose(format!("{}!", name), &span, msg, None);
+        }
+        self.definitions = mem::replace(&mut walk.definitions, Vec::new());
+        let (sites, matched) = self.match_sites(walk.sites);
+        self.sites = sites;
+        self.matched = matched;
+    }
+
+    // The invocations among `sites` to expand, each with the arm it matched.
+    // Those that cannot be expanded are left as they are, with a diagnostic.
+    fn match_sites(&mut self, sites: Vec<Site>) -> (Vec<Site>, Vec<(usize, Bindings, RuleMatch)>) {
+        let mut pending = Vec::new();
+        let mut matched = Vec::new();
+        for site in sites {
+            let def = match site.def {
+                Some(def) if self.filter.allows(&site.path) => def,
+                _ => continue,
+            };
+            match expand_site(&site, &self.definitions[def]) {
+                Ok(found) => {
+                    pending.push(site);
+                    matched.push(found);
+                }
+                Err((msg, closest)) => self.diagnose(format!("{}!", site.path), &site.span, msg, closest),
+            }
+        }
+        (pending, matched)
+    }
+
+    // Record that the invocation or definition at `span` cannot be expanded,
+    // unless an earlier step already has.
+    fn diagnose(&mut self, name: String, span: &Span, message: String, closest: Option<ArmFailure>) {
+        let info = span_info(span);
+        if !self.reported.insert((span_to_string(&info), span.expn)) {
+            return;
+        }
+        self.diagnostics.push(Diagnostic {
+            step: self.index,
+            name: name,
+            span: info,
+            message: message,
+            closest: closest,
+        });
+    }
+
+    // Expand every pending invocation, or only the `chosen`th, recording an
+    // event for each. The tokens each takes from its macro's body are marked
+    // with a new syntax context; those from its arguments keep theirs.
+    fn expand_selected(&mut self, chosen: Option<usize>) {
+        let mut outputs = Vec::new();
+        let selected: Vec<usize> = (0..self.sites.len())
+                                       .filter(|&i| chosen.map_or(true, |chosen| chosen == i))
+                                       .collect();
+        for &site in &selected {
+            let (arm, ref bindings, ref rule) = self.matched[site];
+            let def = self.sites[site].def.unwrap();
+            let parent = self.sites[site].span.expn;
+            let event = Event {
+                id: self.events.len(),
+                step: self.index + 1,
+                name: format!("{}!", self.sites[site].path),
+                call_site: span_info(&self.sites[site].span),
+                callee: self.sites[site].def.map(|def| span_info(&self.definitions[def].span)),
+                output: None,
+                parent: parent,
+                root: parent.map_or(self.events.len(), |parent| self.events[parent].root),
+                rule: Some(rule.clone()),
+            };
+            let mut body = self.definitions[def].arms[arm].transcriber.clone();
+            self.contexts.mark(&mut body, event.id);
+            let mut output = mbe::transcribe(&body, bindings).expect("a matched arm transcribes");
+            tokens::mark(&mut output, event.id);
+            self.events.push(event);
+            outputs.push(output);
+        }
+        self.index += 1;
+
+        // An output that defines a macro can change what the invocations after
+        // it resolve to, so the crate is walked again; otherwise only the
+        // outputs are. They are spliced in from the last, so that each goes
+        // where its invocation still is.
+        let defines = outputs.iter().any(|output| walk::defines_macro(output));
+        for (&i, output) in selected.iter().zip(outputs).rev() {
+            let site = self.sites.remove(i);
+            self.matched.remove(i);
+            let (removed, placed) = walk::splice(&mut self.krate, &site, output);
+            for later in &mut self.sites[i..] {
+                walk::shift(later, &site.at, removed, placed);
+            }
+            if defines {
+                continue;
+            }
+            let (&offset, group) = site.at.split_last().unwrap();
+            let mut walk = Walk::within((*site.scope).clone());
+            walk.walk_part(&walk::group(&self.krate, group)[offset..offset + placed], group, offset, !site.expr);
+            let (sites, matched) = self.match_sites(walk.sites);
+            for (j, (found, matched)) in sites.into_iter().zip(matched).enumerate() {
+                self.sites.insert(i + j, found);
+                self.matched.insert(i + j, matched);
+            }
+        }
+        if defines {
+            self.find_sites();
+        }
+    }
+
+    // The event of the expansion that produced `span`, and the side of its
+    // invocation the token was written on.
+    fn mark(&self, span: &Span) -> Option<(usize, Side)> {
+        span.expn.map(|id| (id, events::side(&self.events, id, &span_info(span))))
+    }
+
+    // Walk the call sites of the expansions that produced `span`, innermost first.
+    fn backtrace(&self, span: &Span) -> Vec<Frame> {
+        let mut frames = Vec::new();
+        let mut expn = span.expn;
+        while let Some(id) = expn {
+            let event = &self.events[id];
+            frames.push(Frame {
+                name: event.name.clone(),
+                call_site: span_to_string(&event.call_site),
+            });
+            expn = event.parent;
+        }
+        frames
+    }
+}
+
+impl Backend for ExpandData {
+    type Crate = Vec<Token>;
+
+    fn krate(&self) -> &Vec<Token> {
+        &self.krate
+    }
+
+    fn filename(&self) -> &str {
+        &self.filename
+    }
+
+    fn index(&self) -> usize {
+        self.index
+    }
+
+    fn events(&self) -> &[Event] {
+        &self.events
+    }
+
+    fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
+    fn reports_rules(&self) -> bool {
+        true
+    }
+
+    fn pending(&mut self) -> Vec<Invocation> {
+        self.sites.iter().map(|site| {
+            Invocation {
+                name: format!("{}!", site.path),
+                location: span_to_string(&span_info(&site.span)),
+                backtrace: self.backtrace(&site.span),
+            }
+        }).collect()
+    }
+
+    fn definition_site(&self, i: usize) -> Option<String> {
+        let site = &self.sites[i];
+        site.def.map(|def| span_to_string(&span_info(&self.definitions[def].span)))
+    }
+
+    fn expand_all(&mut self) -> Result<HashSet<usize>, String> {
+        self.expand_selected(None);
+        Ok(HashSet::new())
+    }
+
+    // Invocations that cannot be expanded are diagnosed before they would be
+    // pending, so the chosen one always is.
+    fn expand_one(&mut self, chosen: usize) -> Result<bool, String> {
+        self.expand_selected(Some(chosen));
+        Ok(true)
+    }
+
+    fn print_step(&self) -> Result<Vec<(String, String)>, String> {
+        let shown;
+        let krate = match self.hygiene {
+            Some(Hygiene::Marks) => {
+                shown = hygiene::show_marks(&self.krate);
+                &shown
+            }
+            Some(Hygiene::Rename) => {
+                shown = hygiene::rename(&self.krate);
+                &shown
+            }
+            None => &self.krate,
+        };
+        let text = if self.provenance {
+            printer::print(krate, &ProvenanceAnn::new(&self.events))
+        } else {
+            printer::print(krate, &NoAnn)
+        };
+        Ok(vec![(self.filename.clone(), text)])
+    }
+
+    fn print_origins(&self, source: &str, origins: &mut Origins) -> Result<String, String> {
+        let cell = RefCell::new(mem::replace(origins, Origins::default()));
+        let text = {
+            let ann = MarkingAnn::new(|span: &Span| {
+                self.mark(span).and_then(|(_, side)| {
+                    annotate::origin(&self.events, &self.filename, source, span)
+                        .map(|origin| (cell.borrow_mut().id(origin), side))
+                })
+            });
+            printer::print(&self.krate, &ann)
+        };
+        *origins = cell.into_inner();
+        Ok(text)
+    }
+
+    fn print_outputs(&self) -> Result<String, String> {
+        let ann = MarkingAnn::new(|span: &Span| self.mark(span));
+        Ok(printer::print(&self.krate, &ann))
+    }
+}
+
+pub type ExpansionStep = session::ExpansionStep;
+
+// The expansion of a crate, one step at a time.
+pub struct ExpansionSession {
+    steps: Steps<ExpandData>,
+}
+
+impl ExpansionSession {
+    // Parse the crate in `input`. Nothing is expanded until the first step
+    // after step 0 is asked for.
+    pub fn new(input: Input, options: SessionOptions) -> Result<ExpansionSession, String> {
+        let data = try!(ExpandData::new(&input, options.filter.clone(), options.provenance,
+                                        options.hygiene));
+        Ok(ExpansionSession { steps: Steps::new(data, options) })
+    }
+
+    // Why the steps ended before every invocation was expanded, if they did.
+    pub fn error(&self) -> Option<&StepError> {
+        self.steps.error()
+    }
+
+    // Where the code at `line:col` of the module file `file` in the latest
+    // step, as the step's `files` hold it, came from, through every expansion
+    // down to the input.
+    pub fn whence(&self, file: &str, line: usize, col: usize) -> Result<Whence, String> {
+        self.steps.whence(file, line, col)
+    }
+
+    // The crate at the latest step. Earlier steps are kept only as their
+    // printed files.
+    pub fn krate(&self) -> &Vec<Token> {
+        self.steps.backend().krate()
+    }
+
+    // The printed files of step `index`, if the session has reached it.
+    pub fn step_files(&self, index: usize) -> Option<Vec<(String, String)>> {
+        self.steps.step_files(index)
+    }
+
+    // What an invocation in the input file expands to in the latest step, with
+    // every expansion nested in it resolved.
+    pub fn outcome(&self, target: Target) -> Result<Outcome, String> {
+        self.steps.outcome(target)
+    }
+}
+
+impl Iterator for ExpansionSession {
+    type Item = ExpansionStep;
+
+    fn next(&mut self) -> Option<ExpansionStep> {
+        self.steps.next()
+    }
+}
+
+// Run the command in `options`, as the binary does.
+pub fn run(options: &Options) -> Result<(), String> {
+    let input = Input::File(options.filename.clone().into());
+    let mut session = try!(ExpansionSession::new(input, options.session_options()));
+    engine::run(options, &mut session.steps)
+}
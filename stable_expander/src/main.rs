@@ -0,0 +1,7 @@
+extern crate macro_expander_core;
+extern crate stable_expander;
+
+// Given some filepath, repeatedly expand and write output until no further expansion possible
+fn main() {
+    macro_expander_core::main(stable_expander::run);
+}
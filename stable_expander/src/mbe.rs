@@ -0,0 +1,632 @@
+// `macro_rules!` by example: parsing definitions into arms, matching an
+// invocation's tokens against them, and transcribing the matched arm. Fragments
+// such as `$e:expr` are parsed with syn to find how many tokens they take.
+
+use proc_macro2::TokenStream;
+use syn;
+use syn::parse::{ParseStream, Parser};
+
+use tokens::{self, Delim, Span, Token, TokenKind};
+
+use std::collections::HashMap;
+
+// The kind of a metavariable, `$x:kind`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fragment {
+    Ident,
+    Lifetime,
+    Literal,
+    Tt,
+    Expr,
+    Ty,
+    Pat,
+    Stmt,
+    Block,
+    Item,
+    Path,
+    Meta,
+    Vis,
+}
+
+impl Fragment {
+    fn from_str(s: &str) -> Option<Fragment> {
+        Some(match s {
+            "ident" => Fragment::Ident,
+            "lifetime" => Fragment::Lifetime,
+            "literal" => Fragment::Literal,
+            "tt" => Fragment::Tt,
+            "expr" => Fragment::Expr,
+            "ty" => Fragment::Ty,
+            "pat" => Fragment::Pat,
+            "stmt" => Fragment::Stmt,
+            "block" => Fragment::Block,
+            "item" => Fragment::Item,
+            "path" => Fragment::Path,
+            "meta" => Fragment::Meta,
+            "vis" => Fragment::Vis,
+            _ => return None,
+        })
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Fragment::Ident => "ident",
+            Fragment::Lifetime => "lifetime",
+            Fragment::Literal => "literal",
+            Fragment::Tt => "tt",
+            Fragment::Expr => "expr",
+            Fragment::Ty => "ty",
+            Fragment::Pat => "pat",
+            Fragment::Stmt => "stmt",
+            Fragment::Block => "block",
+            Fragment::Item => "item",
+            Fragment::Path => "path",
+            Fragment::Meta => "meta",
+            Fragment::Vis => "vis",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RepeatOp {
+    ZeroOrMore,
+    OneOrMore,
+    ZeroOrOne,
+}
+
+#[derive(Clone, Debug)]
+pub enum Matcher {
+    // A token that must be there as it is.
+    Token(Token),
+    Group(Delim, Vec<Matcher>),
+    Var(String, Fragment),
+    // `$( ... ) sep op`.
+    Repeat(Vec<Matcher>, Option<Token>, RepeatOp),
+}
+
+pub struct Arm {
+    // The matcher as written, with its delimiters.
+    pub lhs: Token,
+    pub matcher: Vec<Matcher>,
+    pub transcriber: Vec<Token>,
+}
+
+pub struct MacroDef {
+    // The whole `macro_rules!` item.
+    pub span: Span,
+    pub arms: Vec<Arm>,
+}
+
+// What a metavariable matched: its tokens, or one binding per repetition.
+#[derive(Clone, Debug)]
+pub enum Binding {
+    One(Vec<Token>, Fragment),
+    Seq(Vec<Binding>),
+}
+
+pub type Bindings = HashMap<String, Binding>;
+
+// Where the furthest attempt at matching an arm stopped.
+#[derive(Clone, Debug)]
+pub struct Failure {
+    // The offending token, or `None` at the end of the invocation.
+    pub token: Option<Token>,
+    pub expected: String,
+    // Indices down through the groups to the offending token, to compare how
+    // far attempts got.
+    progress: Vec<usize>,
+}
+
+impl Failure {
+    pub fn found(&self) -> String {
+        match self.token {
+            Some(ref token) => format!("the token `{}`", token.describe()),
+            None => "end of macro invocation".to_owned(),
+        }
+    }
+}
+
+// The index of the failure that got furthest into the invocation, the first on
+// ties, as the arm closest to matching.
+pub fn closest(failures: &[Failure]) -> usize {
+    let mut closest = 0;
+    for (i, failure) in failures.iter().enumerate() {
+        if failure.progress > failures[closest].progress {
+            closest = i;
+        }
+    }
+    closest
+}
+
+impl MacroDef {
+    // Parse the body of `macro_rules! name { ... }`, whose tokens are `body`.
+    pub fn parse(name: &str, span: Span, body: &[Token]) -> Result<MacroDef, String> {
+        let mut arms = Vec::new();
+        let mut i = 0;
+        while i < body.len() {
+            let matcher = match body[i].group() {
+                Some((_, tokens)) => try!(parse_matcher(tokens)),
+                None => return Err(format!("expected a matcher in `{}!`, found `{}`",
+                                           name, body[i].describe())),
+            };
+            if !(i + 2 < body.len() && body[i + 1].is_punct('=') && body[i + 2].is_punct('>')) {
+                return Err(format!("expected `=>` after a matcher of `{}!`", name));
+            }
+            let transcriber = match body.get(i + 3).and_then(|token| token.group()) {
+                Some((_, tokens)) => tokens.to_vec(),
+                None => return Err(format!("expected a transcriber after `=>` in `{}!`", name)),
+            };
+            arms.push(Arm {
+                lhs: body[i].clone(),
+                matcher: matcher,
+                transcriber: transcriber,
+            });
+            i += 4;
+            if i < body.len() {
+                if !body[i].is_punct(';') {
+                    return Err(format!("expected `;` between the arms of `{}!`", name));
+                }
+                i += 1;
+            }
+        }
+        if arms.is_empty() {
+            return Err(format!("`{}!` has no arms", name));
+        }
+        Ok(MacroDef { span: span, arms: arms })
+    }
+}
+
+fn parse_matcher(tokens: &[Token]) -> Result<Vec<Matcher>, String> {
+    let mut matchers = Vec::new();
+    let mut i = 0;
+    while i < tokens.len() {
+        let token = &tokens[i];
+        if token.is_punct('$') && i + 1 < tokens.len() {
+            let next = &tokens[i + 1];
+            if let Some((Delim::Paren, inner)) = next.group() {
+                let (sep, op, len) = try!(parse_repeat_op(&tokens[i + 2..]));
+                matchers.push(Matcher::Repeat(try!(parse_matcher(inner)), sep, op));
+                i += 2 + len;
+                continue;
+            }
+            if let Some(name) = next.ident() {
+                if i + 3 < tokens.len() && tokens[i + 2].is_punct(':') {
+                    let kind = tokens[i + 3].ident().and_then(Fragment::from_str);
+                    let kind = try!(kind.ok_or(format!("invalid fragment specifier `{}`",
+                                                       tokens[i + 3].describe())));
+                    matchers.push(Matcher::Var(name.to_owned(), kind));
+                    i += 4;
+                    continue;
+                }
+                return Err(format!("missing fragment specifier for `${}`", name));
+            }
+        }
+        match token.kind {
+            TokenKind::Group(delim, ref inner) => {
+                matchers.push(Matcher::Group(delim, try!(parse_matcher(inner))));
+            }
+            _ => matchers.push(Matcher::Token(token.clone())),
+        }
+        i += 1;
+    }
+    Ok(matchers)
+}
+
+// The separator and operator after `$( ... )`, and how many tokens they take.
+fn parse_repeat_op(tokens: &[Token]) -> Result<(Option<Token>, RepeatOp, usize), String> {
+    let op = |token: &Token| {
+        match token.kind {
+            TokenKind::Punct('*', _) => Some(RepeatOp::ZeroOrMore),
+            TokenKind::Punct('+', _) => Some(RepeatOp::OneOrMore),
+            TokenKind::Punct('?', _) => Some(RepeatOp::ZeroOrOne),
+            _ => None,
+        }
+    };
+    match (tokens.get(0), tokens.get(1)) {
+        (Some(first), _) if op(first).is_some() => Ok((None, op(first).unwrap(), 1)),
+        (Some(sep), Some(second)) if op(second).is_some() => {
+            Ok((Some(sep.clone()), op(second).unwrap(), 2))
+        }
+        _ => Err("expected one of `*`, `+` or `?` after a repetition".to_owned()),
+    }
+}
+
+// Match the tokens of one invocation against the arms of a definition.
+pub struct Match<'d> {
+    def: &'d MacroDef,
+    // The furthest failure of the arm being matched.
+    failure: Option<Failure>,
+}
+
+impl<'d> Match<'d> {
+    pub fn new(def: &'d MacroDef) -> Match<'d> {
+        Match { def: def, failure: None }
+    }
+
+    // The index of the first arm that matches `input`, with its bindings and
+    // the failures of the arms before it, or the failure of every arm.
+    pub fn find_arm(&mut self, input: &[Token])
+                    -> Result<(usize, Bindings, Vec<Failure>), Vec<Failure>> {
+        let mut failures = Vec::new();
+        for (i, arm) in self.def.arms.iter().enumerate() {
+            self.failure = None;
+            let ends = self.match_seq(&arm.matcher, input, 0, &[]);
+            if let Some(furthest) = ends.iter().map(|&(end, _)| end).max() {
+                if furthest < input.len() {
+                    self.fail(input, furthest, &[], "no more tokens".to_owned());
+                }
+            }
+            if let Some((_, bindings)) = ends.into_iter().find(|&(end, _)| end == input.len()) {
+                return Ok((i, bindings, failures));
+            }
+            failures.push(self.failure.take().unwrap_or(Failure {
+                token: input.first().cloned(),
+                expected: "a repetition that takes tokens".to_owned(),
+                progress: vec![0],
+            }));
+        }
+        Err(failures)
+    }
+
+    fn fail(&mut self, input: &[Token], pos: usize, path: &[usize], expected: String) {
+        let mut progress = path.to_vec();
+        progress.push(pos);
+        if self.failure.as_ref().map_or(false, |failure| failure.progress > progress) {
+            return;
+        }
+        self.failure = Some(Failure {
+            token: input.get(pos).cloned(),
+            expected: expected,
+            progress: progress,
+        });
+    }
+
+    // Every way of matching `matchers` from `start`, as the position after the
+    // match and the bindings, keeping the first way to reach each position.
+    fn match_seq(&mut self,
+                 matchers: &[Matcher],
+                 input: &[Token],
+                 start: usize,
+                 path: &[usize]) -> Vec<(usize, Bindings)> {
+        let mut states = vec![(start, Bindings::new())];
+        for matcher in matchers {
+            let mut next: Vec<(usize, Bindings)> = Vec::new();
+            for (pos, bindings) in states {
+                for (end, more) in self.match_one(matcher, input, pos, path) {
+                    if next.iter().any(|&(seen, _)| seen == end) {
+                        continue;
+                    }
+                    let mut merged = bindings.clone();
+                    merged.extend(more);
+                    next.push((end, merged));
+                }
+            }
+            if next.is_empty() {
+                return next;
+            }
+            states = next;
+        }
+        states
+    }
+
+    fn match_one(&mut self,
+                 matcher: &Matcher,
+                 input: &[Token],
+                 pos: usize,
+                 path: &[usize]) -> Vec<(usize, Bindings)> {
+        match *matcher {
+            Matcher::Token(ref expected) => {
+                if input.get(pos).map_or(false, |token| token.same_as(expected)) {
+                    return vec![(pos + 1, Bindings::new())];
+                }
+                self.fail(input, pos, path, format!("`{}`", expected.describe()));
+                Vec::new()
+            }
+            Matcher::Group(delim, ref inner) => {
+                let tokens = match input.get(pos).and_then(|token| token.group()) {
+                    Some((found, tokens)) if found == delim => tokens,
+                    _ => {
+                        self.fail(input, pos, path, format!("`{}`", delim.open()));
+                        return Vec::new();
+                    }
+                };
+                let mut inner_path = path.to_vec();
+                inner_path.push(pos);
+                let ends = self.match_seq(inner, tokens, 0, &inner_path);
+                if let Some(&(_, ref bindings)) = ends.iter().find(|&&(end, _)| end == tokens.len()) {
+                    return vec![(pos + 1, bindings.clone())];
+                }
+                if let Some(furthest) = ends.iter().map(|&(end, _)| end).max() {
+                    self.fail(tokens, furthest, &inner_path, "no more tokens".to_owned());
+                }
+                Vec::new()
+            }
+            Matcher::Var(ref name, kind) => {
+                match match_fragment(kind, &input[pos..]) {
+                    Some(len) => {
+                        let mut bindings = Bindings::new();
+                        bindings.insert(name.clone(),
+                                        Binding::One(input[pos..pos + len].to_vec(), kind));
+                        vec![(pos + len, bindings)]
+                    }
+                    None => {
+                        self.fail(input, pos, path, format!("`${}:{}`", name, kind.name()));
+                        Vec::new()
+                    }
+                }
+            }
+            Matcher::Repeat(ref inner, ref sep, op) => self.match_repeat(inner, sep, op, input, pos, path),
+        }
+    }
+
+    fn match_repeat(&mut self,
+                    inner: &[Matcher],
+                    sep: &Option<Token>,
+                    op: RepeatOp,
+                    input: &[Token],
+                    pos: usize,
+                    path: &[usize]) -> Vec<(usize, Bindings)> {
+        let mut vars = Vec::new();
+        var_names(inner, &mut vars);
+        let mut results = Vec::new();
+        if op != RepeatOp::OneOrMore {
+            results.push((pos, Vec::new()));
+        }
+        let mut frontier: Vec<(usize, Vec<Bindings>)> = vec![(pos, Vec::new())];
+        while !frontier.is_empty() {
+            let mut next = Vec::new();
+            for (at, iterations) in frontier {
+                let mut start = at;
+                if !iterations.is_empty() {
+                    if let Some(ref sep) = *sep {
+                        if !input.get(at).map_or(false, |token| token.same_as(sep)) {
+                            continue;
+                        }
+                        start += 1;
+                    }
+                }
+                for (end, bindings) in self.match_seq(inner, input, start, path) {
+                    if end == at || results.iter().any(|&(seen, _)| seen == end) {
+                        continue;
+                    }
+                    let mut iterations = iterations.clone();
+                    iterations.push(bindings);
+                    results.push((end, iterations.clone()));
+                    if op != RepeatOp::ZeroOrOne {
+                        next.push((end, iterations));
+                    }
+                }
+            }
+            frontier = next;
+        }
+        results.into_iter().map(|(end, iterations)| {
+            let mut bindings = Bindings::new();
+            for &(ref name, _) in &vars {
+                let seq = iterations.iter().filter_map(|b| b.get(name).cloned()).collect();
+                bindings.insert(name.clone(), Binding::Seq(seq));
+            }
+            (end, bindings)
+        }).collect()
+    }
+}
+
+// The metavariables in `matchers` and their kinds, in the order they are written.
+pub fn var_names(matchers: &[Matcher], vars: &mut Vec<(String, Fragment)>) {
+    for matcher in matchers {
+        match *matcher {
+            Matcher::Var(ref name, kind) => vars.push((name.clone(), kind)),
+            Matcher::Group(_, ref inner) | Matcher::Repeat(ref inner, _, _) => var_names(inner, vars),
+            Matcher::Token(_) => {}
+        }
+    }
+}
+
+// How many tokens at the start of `input` a fragment of `kind` takes.
+fn match_fragment(kind: Fragment, input: &[Token]) -> Option<usize> {
+    let first = match input.first() {
+        Some(first) => first,
+        None => return if kind == Fragment::Vis { Some(0) } else { None },
+    };
+    let is_lifetime = first.is_punct('\'') && input.get(1).map_or(false, |t| t.ident().is_some());
+    match kind {
+        Fragment::Tt => Some(if is_lifetime { 2 } else { 1 }),
+        Fragment::Ident => first.ident().and_then(|ident| if ident == "_" { None } else { Some(1) }),
+        Fragment::Lifetime => if is_lifetime { Some(2) } else { None },
+        Fragment::Literal => {
+            let is_literal = |token: &Token| {
+                match token.kind {
+                    TokenKind::Literal(_) => true,
+                    TokenKind::Ident(ref ident) => ident == "true" || ident == "false",
+                    _ => false,
+                }
+            };
+            if is_literal(first) {
+                Some(1)
+            } else if first.is_punct('-') && input.get(1).map_or(false, |t| is_literal(t)) {
+                Some(2)
+            } else {
+                None
+            }
+        }
+        Fragment::Vis => {
+            if first.is_ident("pub") {
+                match input.get(1).and_then(|token| token.group()) {
+                    Some((Delim::Paren, _)) => Some(2),
+                    _ => Some(1),
+                }
+            } else if first.is_ident("crate") {
+                Some(1)
+            } else {
+                Some(0)
+            }
+        }
+        Fragment::Meta => {
+            let path = try_opt!(parse_prefix(input, |input| input.parse::<syn::Path>().map(|_| ())));
+            match input.get(path) {
+                Some(token) if token.group().is_some() => Some(path + 1),
+                Some(token) if token.is_punct('=') && input.get(path + 1).is_some() => Some(path + 2),
+                _ => Some(path),
+            }
+        }
+        Fragment::Expr => parse_prefix(input, |input| input.parse::<syn::Expr>().map(|_| ())),
+        Fragment::Ty => parse_prefix(input, |input| input.parse::<syn::Type>().map(|_| ())),
+        Fragment::Pat => parse_prefix(input, |input| input.parse::<syn::Pat>().map(|_| ())),
+        Fragment::Block => parse_prefix(input, |input| input.parse::<syn::Block>().map(|_| ())),
+        Fragment::Item => parse_prefix(input, |input| input.parse::<syn::Item>().map(|_| ())),
+        Fragment::Path => parse_prefix(input, |input| input.parse::<syn::Path>().map(|_| ())),
+        Fragment::Stmt => {
+            if first.is_ident("let") {
+                return parse_prefix(input, parse_local);
+            }
+            parse_prefix(input, |input| input.parse::<syn::Item>().map(|_| ()))
+                .or_else(|| parse_prefix(input, |input| input.parse::<syn::Expr>().map(|_| ())))
+        }
+    }
+}
+
+// A `let` statement without its semicolon, as `$s:stmt` takes it.
+fn parse_local(input: ParseStream) -> syn::Result<()> {
+    try!(input.parse::<Token![let]>());
+    try!(input.parse::<syn::Pat>());
+    if input.peek(Token![:]) {
+        try!(input.parse::<Token![:]>());
+        try!(input.parse::<syn::Type>());
+    }
+    if input.peek(Token![=]) {
+        try!(input.parse::<Token![=]>());
+        try!(input.parse::<syn::Expr>());
+    }
+    Ok(())
+}
+
+// How many tokens at the start of `tokens` `parse` takes, if it succeeds.
+fn parse_prefix<F>(tokens: &[Token], parse: F) -> Option<usize>
+    where F: Fn(ParseStream) -> syn::Result<()>
+{
+    let parser = |input: ParseStream| -> syn::Result<usize> {
+        try!(parse(input));
+        let rest: TokenStream = try!(input.parse());
+        Ok(rest.into_iter().count())
+    };
+    let rest = try_opt!(parser.parse2(tokens::to_stream(tokens)).ok());
+    if rest == tokens.len() { None } else { Some(tokens.len() - rest) }
+}
+
+// Substitute `bindings` into the transcriber of an arm.
+pub fn transcribe(transcriber: &[Token], bindings: &Bindings) -> Result<Vec<Token>, String> {
+    let mut out = Vec::new();
+    try!(transcribe_seq(transcriber, bindings, &mut Vec::new(), &mut out));
+    Ok(out)
+}
+
+// The binding of `name` in the repetitions given by `indices`, outermost first.
+fn lookup<'b>(bindings: &'b Bindings, name: &str, indices: &[usize]) -> Option<&'b Binding> {
+    let mut binding = try_opt!(bindings.get(name));
+    for &i in indices {
+        binding = match *binding {
+            Binding::Seq(ref seq) => try_opt!(seq.get(i)),
+            Binding::One(..) => return Some(binding),
+        };
+    }
+    Some(binding)
+}
+
+fn transcribe_seq(tokens: &[Token],
+                  bindings: &Bindings,
+                  indices: &mut Vec<usize>,
+                  out: &mut Vec<Token>) -> Result<(), String> {
+    let mut i = 0;
+    while i < tokens.len() {
+        let token = &tokens[i];
+        if token.is_punct('$') && i + 1 < tokens.len() {
+            let next = &tokens[i + 1];
+            if let Some((Delim::Paren, inner)) = next.group() {
+                let (sep, _, len) = try!(parse_repeat_op(&tokens[i + 2..]));
+                let count = try!(repeat_count(inner, bindings, indices));
+                for n in 0..count {
+                    if n > 0 {
+                        if let Some(ref sep) = sep {
+                            out.push(sep.clone());
+                        }
+                    }
+                    indices.push(n);
+                    try!(transcribe_seq(inner, bindings, indices, out));
+                    indices.pop();
+                }
+                i += 2 + len;
+                continue;
+            }
+            if let Some(name) = next.ident() {
+                if name == "crate" {
+                    out.push(Token { kind: TokenKind::Ident("crate".to_owned()), span: next.span.clone() });
+                    i += 2;
+                    continue;
+                }
+                match lookup(bindings, name, indices) {
+                    Some(&Binding::One(ref matched, kind)) => {
+                        if kind == Fragment::Expr && matched.len() > 1 {
+                            let span = matched[0].span.to(&matched[matched.len() - 1].span);
+                            out.push(Token { kind: TokenKind::Group(Delim::None, matched.clone()), span: span });
+                        } else {
+                            out.extend(matched.iter().cloned());
+                        }
+                        i += 2;
+                        continue;
+                    }
+                    Some(&Binding::Seq(_)) => {
+                        return Err(format!("variable `{}` is still repeating at this depth", name));
+                    }
+                    // Not a metavariable of this macro, as in the definition
+                    // of another macro; left as it is.
+                    None => {}
+                }
+            }
+        }
+        match token.kind {
+            TokenKind::Group(delim, ref inner) => {
+                let mut group = Vec::new();
+                try!(transcribe_seq(inner, bindings, indices, &mut group));
+                out.push(Token { kind: TokenKind::Group(delim, group), span: token.span.clone() });
+            }
+            _ => out.push(token.clone()),
+        }
+        i += 1;
+    }
+    Ok(())
+}
+
+// How many times `$( tokens )` repeats, from the metavariables in it that
+// repeat at this depth, which must agree.
+fn repeat_count(tokens: &[Token], bindings: &Bindings, indices: &[usize]) -> Result<usize, String> {
+    let mut names = Vec::new();
+    transcribed_names(tokens, &mut names);
+    let mut count: Option<(usize, &str)> = None;
+    for name in &names {
+        let len = match lookup(bindings, name, indices) {
+            Some(&Binding::Seq(ref seq)) => seq.len(),
+            _ => continue,
+        };
+        match count {
+            Some((n, other)) if n != len => {
+                return Err(format!("meta-variable `{}` repeats {} times, but `{}` repeats {} times",
+                                   other, n, name, len));
+            }
+            _ => count = Some((len, name)),
+        }
+    }
+    count.map(|(n, _)| n).ok_or("attempted to repeat an expression containing no syntax \
+                                 variables matched as repeating at this depth".to_owned())
+}
+
+fn transcribed_names(tokens: &[Token], names: &mut Vec<String>) {
+    for (i, token) in tokens.iter().enumerate() {
+        if token.is_punct('$') {
+            if let Some(name) = tokens.get(i + 1).and_then(|token| token.ident()) {
+                names.push(name.to_owned());
+            }
+        }
+        if let Some((_, inner)) = token.group() {
+            transcribed_names(inner, names);
+        }
+    }
+}
@@ -0,0 +1,429 @@
+// Printing token trees as source. There is no AST to lay out, so lines are
+// broken after statements, items and attributes in blocks that hold them,
+// blocks are indented by their depth, and spacing follows what each token is.
+
+use tokens::{Delim, Span, Token, TokenKind};
+
+// Annotates printed code: each run of tokens for which `id` gives the same id
+// is preceded by `open` and followed by `close`.
+pub trait Ann {
+    fn id(&self, _span: &Span) -> Option<usize> {
+        None
+    }
+
+    fn open(&self, _id: usize) -> String {
+        String::new()
+    }
+
+    fn close(&self, _id: usize) -> String {
+        String::new()
+    }
+
+    // Whether `open` gives the text of a comment, written as a line comment
+    // before runs that start a line and as a block comment elsewhere.
+    fn comments(&self) -> bool {
+        false
+    }
+}
+
+pub struct NoAnn;
+
+impl Ann for NoAnn {}
+
+// Keywords after which a group is not a call or index.
+const KEYWORDS: &'static [&'static str] = &[
+    "as", "box", "break", "dyn", "else", "for", "if", "impl", "in", "let", "loop", "match",
+    "move", "mut", "ref", "return", "unsafe", "where", "while",
+];
+
+// Keywords after which `<` opens generics.
+const GENERIC_OWNERS: &'static [&'static str] = &["enum", "fn", "impl", "struct", "trait", "type"];
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Role {
+    // Spaced on both sides.
+    Binary,
+    // Written against what follows it: prefix operators, `::`, `#`, `$`, `'`,
+    // a macro's `!` and the `<` of generics.
+    Tight,
+}
+
+#[derive(Clone, PartialEq)]
+enum Prev {
+    Start,
+    Ident(String),
+    Literal,
+    Punct(char, bool, Role),
+    Open(Delim),
+    Close(Delim),
+}
+
+enum Sep {
+    None,
+    Space,
+    Newline,
+}
+
+struct Printer<'a> {
+    out: String,
+    ann: &'a Ann,
+    indent: usize,
+    line_start: bool,
+    prev: Prev,
+    // The token before `prev`.
+    prev2: Prev,
+    // How many `<` of generics are open, and whether `prev` closed one.
+    generics: usize,
+    closed_generics: bool,
+    // The id of the annotated run being printed.
+    open: Option<usize>,
+}
+
+pub fn print(tokens: &[Token], ann: &Ann) -> String {
+    let mut printer = Printer {
+        out: String::new(),
+        ann: ann,
+        indent: 0,
+        line_start: true,
+        prev: Prev::Start,
+        prev2: Prev::Start,
+        generics: 0,
+        closed_generics: false,
+        open: None,
+    };
+    printer.seq(tokens, true);
+    printer.close_run();
+    printer.out.push('\n');
+    printer.out
+}
+
+// Whether a block's contents are printed one statement or item per line.
+fn multiline(tokens: &[Token]) -> bool {
+    tokens.iter().enumerate().any(|(i, token)| {
+        token.is_punct(';') ||
+        token.group().map_or(false, |(delim, _)| delim == Delim::Brace) ||
+        (token.is_punct('=') && tokens.get(i + 1).map_or(false, |next| next.is_punct('>')))
+    })
+}
+
+// Whether the contents of a `None` group need parentheses to stay one
+// expression wherever they are printed.
+fn needs_parens(tokens: &[Token]) -> bool {
+    tokens.len() > 1 && tokens.iter().enumerate().any(|(i, token)| {
+        match token.kind {
+            TokenKind::Punct('.', _) => tokens.get(i + 1).map_or(false, |next| next.is_punct('.')),
+            TokenKind::Punct(':', _) | TokenKind::Punct('?', _) | TokenKind::Punct('\'', _) => false,
+            TokenKind::Punct('!', _) => {
+                tokens.get(i + 1).and_then(|next| next.group()).is_none() || i == 0
+            }
+            TokenKind::Punct(..) => true,
+            TokenKind::Ident(ref ident) => ident == "as",
+            _ => false,
+        }
+    })
+}
+
+fn is_attribute(tokens: &[Token], i: usize) -> bool {
+    match tokens[i].group() {
+        Some((Delim::Bracket, _)) => {
+            (i >= 1 && tokens[i - 1].is_punct('#')) ||
+            (i >= 2 && tokens[i - 1].is_punct('!') && tokens[i - 2].is_punct('#'))
+        }
+        _ => false,
+    }
+}
+
+impl<'a> Printer<'a> {
+    fn close_run(&mut self) {
+        if let Some(id) = self.open.take() {
+            let close = self.ann.close(id);
+            self.out.push_str(&close);
+        }
+    }
+
+    fn newline(&mut self) {
+        if self.out.is_empty() {
+            return;
+        }
+        self.out.push('\n');
+        for _ in 0..self.indent {
+            self.out.push_str("    ");
+        }
+        self.line_start = true;
+    }
+
+    fn write(&mut self, sep: Sep, span: &Span, text: &str) {
+        self.write_run(sep, span, text, true)
+    }
+
+    // A closing delimiter returns to the run its group was in, which a comment
+    // has already named.
+    fn write_close(&mut self, sep: Sep, span: &Span, text: &str) {
+        let announce = !self.ann.comments();
+        self.write_run(sep, span, text, announce)
+    }
+
+    fn write_run(&mut self, sep: Sep, span: &Span, text: &str, announce: bool) {
+        let id = self.ann.id(span);
+        if id != self.open {
+            self.close_run();
+        }
+        match sep {
+            Sep::Newline => self.newline(),
+            Sep::Space if !self.line_start => self.out.push(' '),
+            _ => {}
+        }
+        if id != self.open {
+            match id {
+                Some(id) if announce => {
+                    let open = self.ann.open(id);
+                    if !self.ann.comments() {
+                        self.out.push_str(&open);
+                    } else if self.line_start {
+                        self.out.push_str(&format!("// {}", open));
+                        self.newline();
+                    } else {
+                        self.out.push_str(&format!("/* {} */ ", open));
+                    }
+                }
+                _ => {}
+            }
+            self.open = id;
+        }
+        self.out.push_str(text);
+        self.line_start = false;
+    }
+
+    fn advance(&mut self, prev: Prev) {
+        self.prev2 = ::std::mem::replace(&mut self.prev, prev);
+    }
+
+    // Print a sequence of tokens; `multi` is whether it is the contents of a
+    // block printed one statement or item per line.
+    fn seq(&mut self, tokens: &[Token], multi: bool) {
+        for (i, token) in tokens.iter().enumerate() {
+            let sep = if i == 0 {
+                match self.prev {
+                    Prev::Open(Delim::Brace) if multi => Sep::Newline,
+                    Prev::Open(Delim::Brace) => Sep::Space,
+                    _ => Sep::None,
+                }
+            } else if multi && self.breaks_after(tokens, i - 1) {
+                Sep::Newline
+            } else if self.spaced(token, tokens.get(i + 1)) {
+                Sep::Space
+            } else {
+                Sep::None
+            };
+            self.token(sep, token, tokens.get(i + 1));
+        }
+    }
+
+    // Whether a line break follows `tokens[i]` in a multi-line block.
+    fn breaks_after(&self, tokens: &[Token], i: usize) -> bool {
+        let next = &tokens[i + 1];
+        let token = &tokens[i];
+        if token.is_punct(';') || token.is_punct(',') || is_attribute(tokens, i) {
+            return true;
+        }
+        match token.group() {
+            Some((Delim::Brace, _)) => {
+                !(next.is_ident("else") || next.is_ident("as") || next.is_punct(',') ||
+                  next.is_punct(';') || next.is_punct('.') || next.is_punct('?'))
+            }
+            _ => false,
+        }
+    }
+
+    // Whether a space separates `token` from the one before it.
+    fn spaced(&self, token: &Token, next: Option<&Token>) -> bool {
+        let joined_colons = match self.prev {
+            Prev::Punct(':', true, _) => true,
+            _ => false,
+        };
+        match token.kind {
+            TokenKind::Punct(',', _) | TokenKind::Punct(';', _) |
+            TokenKind::Punct('.', _) | TokenKind::Punct('?', _) => return false,
+            TokenKind::Punct(':', _) => {
+                let path = joined_colons || next.map_or(false, |next| next.is_punct(':'));
+                if !path {
+                    return false;
+                }
+                return match self.prev {
+                    Prev::Ident(_) | Prev::Close(_) | Prev::Start | Prev::Open(_) => false,
+                    Prev::Punct('>', _, _) => false,
+                    _ => !joined_colons,
+                };
+            }
+            _ => {}
+        }
+        // `macro_rules! name`.
+        if let (&Prev::Punct('!', _, _), &Prev::Ident(_)) = (&self.prev, &self.prev2) {
+            if token.ident().is_some() {
+                return true;
+            }
+        }
+        match self.prev {
+            Prev::Start | Prev::Open(Delim::Paren) | Prev::Open(Delim::Bracket) |
+            Prev::Open(Delim::None) => return false,
+            Prev::Punct(_, _, Role::Tight) => return false,
+            Prev::Punct(c, true, _) if token_is_op(token) && c != ',' && c != ';' => return false,
+            Prev::Punct('.', _, _) => return false,
+            _ => {}
+        }
+        match token.kind {
+            TokenKind::Group(Delim::Paren, _) | TokenKind::Group(Delim::Bracket, _) => {
+                match self.prev {
+                    Prev::Ident(ref ident) => KEYWORDS.contains(&&ident[..]),
+                    Prev::Close(Delim::Paren) | Prev::Close(Delim::Bracket) => false,
+                    Prev::Punct('>', _, _) => !self.closed_generics,
+                    _ => true,
+                }
+            }
+            TokenKind::Punct('!', _) => {
+                match self.prev {
+                    Prev::Ident(ref ident) => {
+                        KEYWORDS.contains(&&ident[..]) || next.map_or(false, |next| next.is_punct('='))
+                    }
+                    _ => true,
+                }
+            }
+            TokenKind::Punct('<', _) => !self.opens_generics(),
+            TokenKind::Punct('>', _) => self.generics == 0,
+            _ => true,
+        }
+    }
+
+    // Whether a `<` printed next opens generics, from what is before it.
+    fn opens_generics(&self) -> bool {
+        match self.prev {
+            Prev::Punct(':', _, _) => true,
+            Prev::Ident(ref ident) => {
+                ident.chars().next().map_or(false, |c| c.is_uppercase()) ||
+                ident == "impl" ||
+                match self.prev2 {
+                    Prev::Ident(ref owner) => GENERIC_OWNERS.contains(&&owner[..]),
+                    _ => false,
+                }
+            }
+            _ => false,
+        }
+    }
+
+    // Whether a `-`, `&`, `*` or `!` printed next is a prefix operator.
+    fn prefix(&self) -> bool {
+        match self.prev {
+            Prev::Start | Prev::Open(_) => true,
+            Prev::Punct(_, true, role) => role == Role::Tight,
+            Prev::Punct(c, false, _) => c != '>' || self.generics == 0,
+            Prev::Ident(ref ident) => KEYWORDS.contains(&&ident[..]),
+            Prev::Literal | Prev::Close(_) => false,
+        }
+    }
+
+    fn token(&mut self, sep: Sep, token: &Token, next: Option<&Token>) {
+        match token.kind {
+            TokenKind::Ident(ref ident) => {
+                self.write(sep, &token.span, ident);
+                self.advance(Prev::Ident(ident.clone()));
+            }
+            TokenKind::Literal(ref lit) => {
+                self.write(sep, &token.span, lit);
+                self.advance(Prev::Literal);
+            }
+            TokenKind::Punct(c, joint) => {
+                let mut closed = false;
+                let role = match c {
+                    '#' | '$' | '\'' => Role::Tight,
+                    ':' if self.prev == Prev::Punct(':', true, Role::Tight) => Role::Tight,
+                    ':' if joint && next.map_or(false, |next| next.is_punct(':')) => Role::Tight,
+                    ':' => {
+                        // `$x:expr` in a macro definition.
+                        let fragment = self.prev2 == Prev::Punct('$', false, Role::Tight) &&
+                                       next.map_or(false, |next| next.ident().is_some());
+                        if fragment { Role::Tight } else { Role::Binary }
+                    }
+                    '!' if joint && next.map_or(false, |next| next.is_punct('=')) => Role::Binary,
+                    '!' => match self.prev {
+                        Prev::Ident(ref ident) if !KEYWORDS.contains(&&ident[..]) => Role::Tight,
+                        _ => if self.prefix() { Role::Tight } else { Role::Binary },
+                    },
+                    '<' => {
+                        if self.opens_generics() {
+                            self.generics += 1;
+                            Role::Tight
+                        } else {
+                            Role::Binary
+                        }
+                    }
+                    '>' => {
+                        let arrow = match self.prev {
+                            Prev::Punct('-', true, _) | Prev::Punct('=', true, _) => true,
+                            _ => false,
+                        };
+                        if !arrow && self.generics > 0 {
+                            self.generics -= 1;
+                            closed = true;
+                        }
+                        Role::Binary
+                    }
+                    '-' | '&' | '*' if self.prefix() => Role::Tight,
+                    _ => match self.prev {
+                        // The rest of an operator takes the role of its first character.
+                        Prev::Punct(_, true, role) if c != ',' && c != ';' => role,
+                        _ => Role::Binary,
+                    },
+                };
+                self.write(sep, &token.span, &c.to_string());
+                self.advance(Prev::Punct(c, joint && c != ',' && c != ';', role));
+                self.closed_generics = closed;
+            }
+            TokenKind::Group(Delim::None, ref inner) if !needs_parens(inner) => {
+                if let Some(first) = inner.first() {
+                    self.token(sep, first, inner.get(1));
+                    self.seq_from(inner, 1);
+                }
+            }
+            TokenKind::Group(delim, ref inner) => {
+                let delim = if delim == Delim::None { Delim::Paren } else { delim };
+                let generics = ::std::mem::replace(&mut self.generics, 0);
+                self.write(sep, &token.span, delim.open());
+                self.advance(Prev::Open(delim));
+                if delim == Delim::Brace {
+                    if inner.is_empty() {
+                        self.write_close(Sep::None, &token.span, "}");
+                    } else if multiline(inner) {
+                        self.indent += 1;
+                        self.seq(inner, true);
+                        self.indent -= 1;
+                        self.write_close(Sep::Newline, &token.span, "}");
+                    } else {
+                        self.seq(inner, false);
+                        self.write_close(Sep::Space, &token.span, "}");
+                    }
+                } else {
+                    self.seq(inner, false);
+                    self.write_close(Sep::None, &token.span, delim.close());
+                }
+                self.generics = generics;
+                self.advance(Prev::Close(delim));
+            }
+        }
+    }
+
+    // Print `tokens` from `start`, continuing a sequence begun by the caller.
+    fn seq_from(&mut self, tokens: &[Token], start: usize) {
+        for i in start..tokens.len() {
+            let sep = if self.spaced(&tokens[i], tokens.get(i + 1)) { Sep::Space } else { Sep::None };
+            self.token(sep, &tokens[i], tokens.get(i + 1));
+        }
+    }
+}
+
+// Whether `token` is an operator character that can continue a joined operator.
+fn token_is_op(token: &Token) -> bool {
+    match token.kind {
+        TokenKind::Punct(c, _) => "=<>!&|+-*/%^.:".contains(c),
+        _ => false,
+    }
+}
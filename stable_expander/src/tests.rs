@@ -0,0 +1,229 @@
+// The `macro_rules!` matcher and transcriber, and where the walk puts the
+// output of an invocation: an expression keeps its grouping wherever it starts,
+// and a statement or item is spliced in as it is.
+
+use macro_expander_core::cli::Hygiene;
+use macro_expander_core::session::{Input, SessionOptions, StepError};
+
+use mbe::{self, Binding, MacroDef, Match};
+use printer::{self, NoAnn};
+use tokens::{self, Token};
+
+use super::ExpansionSession;
+
+const ADD: &'static str = "macro_rules! add { ($a:expr, $b:expr) => { $a + $b } }\n";
+
+// The printed crate at the last step of expanding `source`.
+fn expand(source: &str) -> String {
+    let input = Input::Source { name: "test.rs".to_owned(), text: source.to_owned() };
+    let mut session = ExpansionSession::new(input, SessionOptions::default()).unwrap();
+    let last = session.by_ref().last().unwrap();
+    assert!(session.error().is_none());
+    last.files.into_iter().next().unwrap().1
+}
+
+// The line of the last step of expanding `source` that starts with `start`.
+fn line(source: &str, start: &str) -> String {
+    let text = expand(source);
+    let found = text.lines().map(|line| line.trim()).find(|line| line.starts_with(start));
+    found.unwrap_or_else(|| panic!("no line starts with `{}` in\n{}", start, text)).to_owned()
+}
+
+fn tokenize(text: &str) -> Vec<Token> {
+    tokens::tokenize("test.rs", text).unwrap()
+}
+
+fn print(tokens: &[Token]) -> String {
+    printer::print(tokens, &NoAnn).trim().to_owned()
+}
+
+// The definition in `source`, `macro_rules! name { ... }`.
+fn definition(source: &str) -> MacroDef {
+    let tokens = tokenize(source);
+    let name = tokens[2].ident().unwrap().to_owned();
+    MacroDef::parse(&name, tokens[0].span.clone(), tokens[3].group().unwrap().1).unwrap()
+}
+
+// The printed output of `def` for the arguments `args`.
+fn transcribe(def: &MacroDef, args: &str) -> String {
+    let (arm, bindings, _) = Match::new(def).find_arm(&tokenize(args)).ok().unwrap();
+    print(&mbe::transcribe(&def.arms[arm].transcriber, &bindings).unwrap())
+}
+
+#[test]
+fn an_invocation_that_starts_an_operand_keeps_its_grouping() {
+    let source = format!("{}fn f() -> i32 {{ add!(1, 2) * 3 }}\n", ADD);
+    assert_eq!(line(&source, "fn f"), "fn f() -> i32 { (1 + 2) * 3 }");
+}
+
+#[test]
+fn an_invocation_that_a_method_is_called_on_keeps_its_grouping() {
+    let source = format!("{}fn main() {{ add!(1, 2).pow(2); }}\n", ADD);
+    assert_eq!(line(&source, "(1"), "(1 + 2).pow(2);");
+}
+
+#[test]
+fn an_invocation_followed_by_a_cast_keeps_its_grouping() {
+    let source = format!("{}fn f() -> u8 {{ add!(1, 2) as u8 }}\n", ADD);
+    assert_eq!(line(&source, "fn f"), "fn f() -> u8 { (1 + 2) as u8 }");
+}
+
+#[test]
+fn a_nested_invocation_keeps_its_grouping() {
+    let source = "macro_rules! inner { ($e:expr) => { $e + 1 } }\n\
+                  macro_rules! outer { ($e:expr) => { inner!($e) * 2 } }\n\
+                  fn g() -> i32 { outer!(3) }\n";
+    assert_eq!(line(source, "fn g"), "fn g() -> i32 { (3 + 1) * 2 }");
+}
+
+#[test]
+fn an_invocation_in_the_tail_of_a_block_is_spliced_as_it_is() {
+    let source = format!("{}fn f() -> i32 {{ add!(1, 2) }}\n", ADD);
+    assert_eq!(line(&source, "fn f"), "fn f() -> i32 { 1 + 2 }");
+}
+
+#[test]
+fn a_statement_invocation_drops_its_semicolon_when_its_output_ends_the_statement() {
+    let source = "macro_rules! two { () => { let x = 1; let y = 2; } }\n\
+                  fn main() { two!(); }\n";
+    let text = expand(source);
+    assert!(text.contains("let x = 1;\n    let y = 2;\n}"), "{}", text);
+    assert!(!text.contains(";;"), "{}", text);
+}
+
+#[test]
+fn an_item_invocation_is_spliced_as_items() {
+    let source = "macro_rules! items { ($n:ident) => { struct $n; impl $n {} } }\n\
+                  items!(S);\n\
+                  fn main() {}\n";
+    let text = expand(source);
+    assert!(text.contains("struct S;\nimpl S {}\n"), "{}", text);
+}
+
+#[test]
+fn the_step_limit_error_names_the_limit() {
+    let source = "macro_rules! forever { () => { forever!(); } }\n\
+                  fn main() { forever!(); }\n";
+    let input = Input::Source { name: "test.rs".to_owned(), text: source.to_owned() };
+    let options = SessionOptions { max_steps: Some(3), ..SessionOptions::default() };
+    let mut session = ExpansionSession::new(input, options).unwrap();
+    assert_eq!(session.by_ref().count(), 4);
+    match session.error() {
+        Some(&StepError::Limit(ref limit, _)) => {
+            assert_eq!(limit, "step limit of 3 reached with 1 invocation(s) still pending");
+        }
+        _ => panic!("the step limit was not reached"),
+    }
+}
+
+#[test]
+fn whence_takes_a_position_in_the_printed_step() {
+    let source = "macro_rules! double {\n    ($e:expr) => { $e * 2 };\n}\n\
+                  fn main() {\n    let x = double!(1 + 1);\n}\n";
+    let input = Input::Source { name: "test.rs".to_owned(), text: source.to_owned() };
+    let options = SessionOptions { provenance: true, ..SessionOptions::default() };
+    let mut session = ExpansionSession::new(input, options).unwrap();
+    let text = session.by_ref().last().unwrap().files.into_iter().next().unwrap().1;
+    let (line, printed) = text.lines().enumerate().find(|&(_, l)| l.contains("let x")).unwrap();
+    let col = |code: &str| printed.find(code).unwrap() + 1;
+    let whence = session.whence("test.rs", line + 1, col("* 2") + 2).unwrap();
+    assert_eq!(whence.side, Some("callee".to_owned()));
+    let whence = session.whence("test.rs", line + 1, col("1 + 1")).unwrap();
+    assert_eq!(whence.side, Some("call_site".to_owned()));
+    assert!(session.whence("lib.rs", line + 1, 1).is_err());
+}
+
+#[test]
+fn a_renamed_binding_does_not_take_a_name_the_user_wrote() {
+    let source = "macro_rules! m { ($e:expr) => { { let x = 2; $e + x } } }\n\
+                  fn main() { let x_1 = 1; let x = 1; let y = m!(x + x_1); }\n";
+    let input = Input::Source { name: "test.rs".to_owned(), text: source.to_owned() };
+    let options = SessionOptions { hygiene: Some(Hygiene::Rename), ..SessionOptions::default() };
+    let session = ExpansionSession::new(input, options).unwrap();
+    let text = session.last().unwrap().files.into_iter().next().unwrap().1;
+    assert!(text.contains("let x_1 = 1;"), "{}", text);
+    assert!(text.contains("let x_1_1 = 2;"), "{}", text);
+    assert!(text.contains("(x + x_1) + x_1_1"), "{}", text);
+}
+
+#[test]
+fn matches_the_first_arm_that_takes_every_token() {
+    let def = definition("macro_rules! m { ($a:ident) => { one }; ($a:ident, $b:expr) => { two } }");
+    let (arm, bindings, failures) = Match::new(&def).find_arm(&tokenize("x, 1 + 2")).ok().unwrap();
+    assert_eq!(arm, 1);
+    assert_eq!(failures.len(), 1);
+    match bindings["b"] {
+        Binding::One(ref tokens, kind) => {
+            assert_eq!(print(tokens), "1 + 2");
+            assert_eq!(kind.name(), "expr");
+        }
+        Binding::Seq(_) => panic!("`$b` does not repeat"),
+    }
+}
+
+#[test]
+fn binds_one_match_per_repetition() {
+    let def = definition("macro_rules! m { ($($x:expr),*) => { 0 } }");
+    let (_, bindings, _) = Match::new(&def).find_arm(&tokenize("a, b + c, d")).ok().unwrap();
+    match bindings["x"] {
+        Binding::Seq(ref seq) => {
+            let printed: Vec<String> = seq.iter().map(|binding| match *binding {
+                Binding::One(ref tokens, _) => print(tokens),
+                Binding::Seq(_) => panic!("`$x` repeats once"),
+            }).collect();
+            assert_eq!(printed, ["a", "b + c", "d"]);
+        }
+        Binding::One(..) => panic!("`$x` repeats"),
+    }
+}
+
+#[test]
+fn reports_the_arm_that_got_furthest() {
+    let def = definition("macro_rules! m { (a) => {}; (a, b, c) => {} }");
+    let failures = Match::new(&def).find_arm(&tokenize("a, b, d")).err().unwrap();
+    assert_eq!(failures.len(), 2);
+    let closest = mbe::closest(&failures);
+    assert_eq!(closest, 1);
+    assert_eq!(failures[closest].found(), "the token `d`");
+}
+
+#[test]
+fn reports_the_end_of_the_invocation() {
+    let def = definition("macro_rules! m { ($a:expr, $b:expr) => {} }");
+    let failures = Match::new(&def).find_arm(&tokenize("1")).err().unwrap();
+    assert_eq!(failures[0].found(), "end of macro invocation");
+}
+
+#[test]
+fn transcribes_repetitions_with_their_separator() {
+    let def = definition("macro_rules! m { ($($x:ident),*) => { [$($x * 2);*] } }");
+    assert_eq!(transcribe(&def, "a, b, c"), "[a * 2; b * 2; c * 2]");
+    assert_eq!(transcribe(&def, ""), "[]");
+}
+
+#[test]
+fn transcribes_nested_repetitions() {
+    let def = definition("macro_rules! m { ($($k:ident => [$($v:expr),*]);*) => { [$($(f($k, $v)),*),*] } }");
+    assert_eq!(transcribe(&def, "a => [1, 2]; b => [3]"), "[f(a, 1), f(a, 2), f(b, 3)]");
+}
+
+#[test]
+fn keeps_an_expression_fragment_one_expression() {
+    let def = definition("macro_rules! double { ($e:expr) => { $e * 2 } }");
+    assert_eq!(transcribe(&def, "1 + 2"), "(1 + 2) * 2");
+}
+
+#[test]
+fn refuses_repetitions_of_different_lengths() {
+    let def = definition("macro_rules! m { ($($a:ident),* ; $($b:ident),*) => { $($a $b)* } }");
+    let (arm, bindings, _) = Match::new(&def).find_arm(&tokenize("x, y; z")).ok().unwrap();
+    let error = mbe::transcribe(&def.arms[arm].transcriber, &bindings).err().unwrap();
+    assert_eq!(error, "meta-variable `a` repeats 2 times, but `b` repeats 1 times");
+}
+
+#[test]
+fn refuses_a_definition_without_an_arrow() {
+    let tokens = tokenize("macro_rules! m { () {} }");
+    let error = MacroDef::parse("m", tokens[0].span.clone(), tokens[3].group().unwrap().1).err().unwrap();
+    assert_eq!(error, "expected `=>` after a matcher of `m!`");
+}
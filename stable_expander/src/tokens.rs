@@ -0,0 +1,226 @@
+// The token trees the stable backend works on. They are proc-macro2's, converted
+// so that every token keeps the file and position it was written at, and the
+// expansion that produced it, as libsyntax spans do.
+
+use proc_macro2::{self, Delimiter, Spacing, TokenStream, TokenTree};
+
+use std::rc::Rc;
+use std::str::FromStr;
+
+// A 1-based line and a 0-based column, in bytes, as proc-macro2 gives them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Pos {
+    pub line: usize,
+    pub col: usize,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Span {
+    pub file: Rc<String>,
+    pub lo: Pos,
+    pub hi: Pos,
+    // The expansion that produced the token, by event id, or `None` for tokens
+    // written in the input.
+    pub expn: Option<usize>,
+    // The syntax context, 0 for tokens that no macro introduced; see `hygiene`.
+    pub ctxt: usize,
+}
+
+impl Span {
+    // The span from the start of `self` to the end of `other`, in the same file.
+    pub fn to(&self, other: &Span) -> Span {
+        Span { hi: other.hi, .. self.clone() }
+    }
+}
+
+// `None` groups are left by substituting an expression fragment, and keep it
+// one expression as libsyntax's interpolated nodes do.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Delim {
+    Paren,
+    Bracket,
+    Brace,
+    None,
+}
+
+impl Delim {
+    pub fn open(&self) -> &'static str {
+        match *self {
+            Delim::Paren => "(",
+            Delim::Bracket => "[",
+            Delim::Brace => "{",
+            Delim::None => "",
+        }
+    }
+
+    pub fn close(&self) -> &'static str {
+        match *self {
+            Delim::Paren => ")",
+            Delim::Bracket => "]",
+            Delim::Brace => "}",
+            Delim::None => "",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum TokenKind {
+    Ident(String),
+    // A punctuation character, and whether it is joined to the next one in a
+    // multi-character operator such as `=>`.
+    Punct(char, bool),
+    Literal(String),
+    Group(Delim, Vec<Token>),
+}
+
+#[derive(Clone, Debug)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+}
+
+impl Token {
+    pub fn is_ident(&self, name: &str) -> bool {
+        match self.kind {
+            TokenKind::Ident(ref ident) => ident == name,
+            _ => false,
+        }
+    }
+
+    pub fn is_punct(&self, c: char) -> bool {
+        match self.kind {
+            TokenKind::Punct(p, _) => p == c,
+            _ => false,
+        }
+    }
+
+    pub fn ident(&self) -> Option<&str> {
+        match self.kind {
+            TokenKind::Ident(ref ident) => Some(ident),
+            _ => None,
+        }
+    }
+
+    pub fn group(&self) -> Option<(Delim, &[Token])> {
+        match self.kind {
+            TokenKind::Group(delim, ref tokens) => Some((delim, tokens)),
+            _ => None,
+        }
+    }
+
+    // Whether `self` is the same token as `other`, ignoring spans and spacing.
+    pub fn same_as(&self, other: &Token) -> bool {
+        match (&self.kind, &other.kind) {
+            (&TokenKind::Ident(ref a), &TokenKind::Ident(ref b)) => a == b,
+            (&TokenKind::Punct(a, _), &TokenKind::Punct(b, _)) => a == b,
+            (&TokenKind::Literal(ref a), &TokenKind::Literal(ref b)) => a == b,
+            (&TokenKind::Group(da, ref a), &TokenKind::Group(db, ref b)) => {
+                da == db && a.len() == b.len() && a.iter().zip(b).all(|(a, b)| a.same_as(b))
+            }
+            _ => false,
+        }
+    }
+
+    // The token as it is written, for messages; groups are shown by their
+    // opening delimiter.
+    pub fn describe(&self) -> String {
+        match self.kind {
+            TokenKind::Ident(ref ident) => ident.clone(),
+            TokenKind::Punct(c, _) => c.to_string(),
+            TokenKind::Literal(ref lit) => lit.clone(),
+            TokenKind::Group(Delim::None, ref tokens) => {
+                tokens.first().map_or(String::new(), |token| token.describe())
+            }
+            TokenKind::Group(delim, _) => delim.open().to_owned(),
+        }
+    }
+}
+
+// Give every token in `tokens` the expansion `expn`.
+pub fn mark(tokens: &mut [Token], expn: usize) {
+    for token in tokens {
+        token.span.expn = Some(expn);
+        if let TokenKind::Group(_, ref mut inner) = token.kind {
+            mark(inner, expn);
+        }
+    }
+}
+
+// Split `text`, the contents of `file`, into token trees.
+pub fn tokenize(file: &str, text: &str) -> Result<Vec<Token>, String> {
+    let stream = try!(TokenStream::from_str(text).map_err(|e| {
+        format!("could not tokenize {}: {:?}", file, e)
+    }));
+    Ok(from_stream(&Rc::new(file.to_owned()), stream))
+}
+
+fn from_stream(file: &Rc<String>, stream: TokenStream) -> Vec<Token> {
+    stream.into_iter().map(|tree| {
+        let span = span_of(file, tree.span());
+        let kind = match tree {
+            TokenTree::Ident(ident) => TokenKind::Ident(ident.to_string()),
+            TokenTree::Punct(punct) => {
+                TokenKind::Punct(punct.as_char(), punct.spacing() == Spacing::Joint)
+            }
+            TokenTree::Literal(lit) => TokenKind::Literal(lit.to_string()),
+            TokenTree::Group(group) => {
+                let delim = match group.delimiter() {
+                    Delimiter::Parenthesis => Delim::Paren,
+                    Delimiter::Bracket => Delim::Bracket,
+                    Delimiter::Brace => Delim::Brace,
+                    Delimiter::None => Delim::None,
+                };
+                TokenKind::Group(delim, from_stream(file, group.stream()))
+            }
+        };
+        Token { kind: kind, span: span }
+    }).collect()
+}
+
+fn span_of(file: &Rc<String>, span: proc_macro2::Span) -> Span {
+    let (lo, hi) = (span.start(), span.end());
+    Span {
+        file: file.clone(),
+        lo: Pos { line: lo.line, col: lo.column },
+        hi: Pos { line: hi.line, col: hi.column },
+        expn: None,
+        ctxt: 0,
+    }
+}
+
+// The tokens as a proc-macro2 stream, for parsing fragments with syn. Spans
+// are not carried over; one token tree becomes one token tree.
+pub fn to_stream(tokens: &[Token]) -> TokenStream {
+    tokens.iter().map(|token| {
+        match token.kind {
+            TokenKind::Ident(ref ident) => {
+                if ident.starts_with("r#") {
+                    first_tree(ident)
+                } else {
+                    TokenTree::Ident(proc_macro2::Ident::new(ident, proc_macro2::Span::call_site()))
+                }
+            }
+            TokenKind::Punct(c, joint) => {
+                let spacing = if joint { Spacing::Joint } else { Spacing::Alone };
+                TokenTree::Punct(proc_macro2::Punct::new(c, spacing))
+            }
+            TokenKind::Literal(ref lit) => first_tree(lit),
+            TokenKind::Group(delim, ref inner) => {
+                let delim = match delim {
+                    Delim::Paren => Delimiter::Parenthesis,
+                    Delim::Bracket => Delimiter::Bracket,
+                    Delim::Brace => Delimiter::Brace,
+                    Delim::None => Delimiter::None,
+                };
+                TokenTree::Group(proc_macro2::Group::new(delim, to_stream(inner)))
+            }
+        }
+    }).collect()
+}
+
+// The token that `text`, a single token read from source, tokenizes to.
+fn first_tree(text: &str) -> TokenTree {
+    TokenStream::from_str(text).ok()
+                               .and_then(|stream| stream.into_iter().next())
+                               .expect("a token read from source tokenizes again")
+}
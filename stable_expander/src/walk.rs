@@ -0,0 +1,319 @@
+// Walking the token trees of a crate for `macro_rules!` definitions and the
+// invocations they resolve to, with the textual scoping the compiler uses: a
+// definition is visible after it in its block or module, and after a
+// `#[macro_use]` module too. Each invocation found records where it is, so
+// that its output can be spliced in without walking the crate again, and only
+// the output walked for the invocations it holds.
+
+use mbe::MacroDef;
+use tokens::{Delim, Span, Token, TokenKind};
+
+use std::collections::HashMap;
+use std::rc::Rc;
+
+// Keywords that can be followed by `!` and a group without it being an
+// invocation, as in `if !(a) {}`.
+const KEYWORDS: &'static [&'static str] = &[
+    "as", "box", "break", "const", "continue", "crate", "else", "enum", "extern", "fn", "for",
+    "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut", "pub", "ref", "return",
+    "self", "Self", "static", "struct", "super", "trait", "type", "unsafe", "use", "where", "while",
+];
+
+// A macro invocation, `path!(...)`, `path![...]` or `path! {...}`.
+pub struct Site {
+    pub path: String,
+    // From the path to the closing delimiter.
+    pub span: Span,
+    pub args: Vec<Token>,
+    // The definition it resolves to, by index in the walk's definitions, or
+    // `None` for macros not defined in the crate.
+    pub def: Option<usize>,
+    // Where it is in the crate's token trees: the index of the group at each
+    // level, then that of its first token in the group's tokens.
+    pub at: Vec<usize>,
+    // How many tokens it takes, up to its group.
+    pub len: usize,
+    // Whether it is in expression position, rather than an item or statement.
+    pub expr: bool,
+    // The definitions visible at it, by name, for walking its output.
+    pub scope: Rc<HashMap<String, usize>>,
+}
+
+pub struct Walk {
+    pub definitions: Vec<MacroDef>,
+    pub sites: Vec<Site>,
+    // The definitions that could not be parsed: the macro, its span and why.
+    pub errors: Vec<(String, Span, String)>,
+    // The definitions in scope by name, innermost scope last.
+    scopes: Vec<HashMap<String, usize>>,
+    // The same as one map, shared by the sites found until the scopes change.
+    visible: Option<Rc<HashMap<String, usize>>>,
+    // The index of the group at each level down to the tokens being walked.
+    at: Vec<usize>,
+}
+
+impl Walk {
+    pub fn new() -> Walk {
+        Walk::within(HashMap::new())
+    }
+
+    // A walk starting with the definitions in `scope` visible.
+    pub fn within(scope: HashMap<String, usize>) -> Walk {
+        Walk {
+            definitions: Vec::new(),
+            sites: Vec::new(),
+            errors: Vec::new(),
+            scopes: vec![scope],
+            visible: None,
+            at: Vec::new(),
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Option<usize> {
+        self.scopes.iter().rev().filter_map(|scope| scope.get(name).cloned()).next()
+    }
+
+    fn visible(&mut self) -> Rc<HashMap<String, usize>> {
+        if self.visible.is_none() {
+            let mut visible = HashMap::new();
+            for scope in &self.scopes {
+                visible.extend(scope.iter().map(|(name, &def)| (name.clone(), def)));
+            }
+            self.visible = Some(Rc::new(visible));
+        }
+        self.visible.as_ref().unwrap().clone()
+    }
+
+    // Walk the tokens of a crate.
+    pub fn walk_crate(&mut self, tokens: &[Token]) {
+        self.walk(tokens, true, 0)
+    }
+
+    // Walk `tokens`, which start at index `offset` of the tokens of the group
+    // at `at`, as `walk` does.
+    pub fn walk_part(&mut self, tokens: &[Token], at: &[usize], offset: usize, block: bool) {
+        self.at = at.to_vec();
+        self.walk(tokens, block, offset)
+    }
+
+    // `block` is whether `tokens` are the contents of a block, module or the
+    // crate, where items and statements start, and `offset` the index of the
+    // first in its group.
+    fn walk(&mut self, tokens: &[Token], block: bool, offset: usize) {
+        // Whether the next token starts an item or statement.
+        let mut stmt_start = block;
+        // Whether the item being walked has `#[macro_use]`.
+        let mut macro_use = false;
+        let mut i = 0;
+        while i < tokens.len() {
+            if let Some(len) = self.definition(&tokens[i..]) {
+                i += len;
+                stmt_start = block;
+                continue;
+            }
+            if let Some((path, len)) = invocation(&tokens[i..]) {
+                let end = i + len;
+                let (args, close) = {
+                    let group = &tokens[end - 1];
+                    (group.group().unwrap().1.to_vec(), group.span.clone())
+                };
+                // At the start of a statement, an invocation is one only if
+                // nothing carries on after it, as in `foo!(a) * 2` or
+                // `foo!(a).len()`, where it is the first operand.
+                let brace = tokens[end - 1].group().map_or(false, |(d, _)| d == Delim::Brace);
+                let ends = brace || tokens.get(end).map_or(true, |t| t.is_punct(';'));
+                let expr = !(block && stmt_start && ends);
+                let name = if path.contains("::") { None } else { self.lookup(&path) };
+                let mut at = self.at.clone();
+                at.push(offset + i);
+                let scope = self.visible();
+                self.sites.push(Site {
+                    path: path,
+                    span: tokens[i].span.to(&close),
+                    args: args,
+                    def: name,
+                    at: at,
+                    len: len,
+                    expr: expr,
+                    scope: scope,
+                });
+                // `foo! { ... }` in statement position ends its statement.
+                stmt_start = !expr && brace;
+                i = end;
+                continue;
+            }
+
+            let token = &tokens[i];
+            if let TokenKind::Group(delim, ref inner) = token.kind {
+                self.at.push(offset + i);
+                if delim == Delim::Brace {
+                    self.scopes.push(HashMap::new());
+                    self.walk(inner, true, 0);
+                    let scope = self.scopes.pop().unwrap();
+                    self.visible = None;
+                    if macro_use {
+                        self.scopes.last_mut().unwrap().extend(scope);
+                    }
+                } else {
+                    self.walk(inner, false, 0);
+                }
+                self.at.pop();
+            }
+
+            // Attributes, `#[...]` or `#![...]`, leave the item or statement
+            // still to come.
+            if token.is_punct('#') {
+                let bang = tokens.get(i + 1).map_or(false, |t| t.is_punct('!'));
+                let at = if bang { i + 2 } else { i + 1 };
+                if let Some((Delim::Bracket, attr)) = tokens.get(at).and_then(|t| t.group()) {
+                    if attr.first().map_or(false, |t| t.is_ident("macro_use")) {
+                        macro_use = true;
+                    }
+                    i = at + 1;
+                    continue;
+                }
+            }
+            let brace = token.group().map_or(false, |(d, _)| d == Delim::Brace);
+            stmt_start = block && (token.is_punct(';') || brace);
+            if stmt_start {
+                macro_use = false;
+            }
+            i += 1;
+        }
+    }
+
+    // If `tokens` start with `macro_rules! name { ... }`, register it and return
+    // how many tokens it takes, with its semicolon.
+    fn definition(&mut self, tokens: &[Token]) -> Option<usize> {
+        if tokens.len() < 4 || !tokens[0].is_ident("macro_rules") || !tokens[1].is_punct('!') {
+            return None;
+        }
+        let name = try_opt!(tokens[2].ident());
+        let (delim, body) = try_opt!(tokens[3].group());
+        let len = if delim != Delim::Brace && tokens.get(4).map_or(false, |t| t.is_punct(';')) {
+            5
+        } else {
+            4
+        };
+        let span = tokens[0].span.to(&tokens[len - 1].span);
+        match MacroDef::parse(name, span.clone(), body) {
+            Ok(def) => {
+                self.definitions.push(def);
+                let index = self.definitions.len() - 1;
+                self.scopes.last_mut().unwrap().insert(name.to_owned(), index);
+                self.visible = None;
+            }
+            Err(msg) => self.errors.push((name.to_owned(), span, msg)),
+        }
+        Some(len)
+    }
+}
+
+// If `tokens` start with an invocation, its path and how many tokens it takes,
+// up to its group.
+fn invocation(tokens: &[Token]) -> Option<(String, usize)> {
+    let mut path = String::new();
+    let mut i = 0;
+    if tokens.len() > 1 && tokens[0].is_punct(':') && tokens[1].is_punct(':') {
+        path.push_str("::");
+        i = 2;
+    }
+    loop {
+        let ident = try_opt!(tokens.get(i).and_then(|t| t.ident()));
+        if path.is_empty() && KEYWORDS.contains(&ident) {
+            return None;
+        }
+        path.push_str(ident);
+        i += 1;
+        let sep = tokens.get(i).map_or(false, |t| t.is_punct(':')) &&
+                  tokens.get(i + 1).map_or(false, |t| t.is_punct(':'));
+        if !sep {
+            break;
+        }
+        path.push_str("::");
+        i += 2;
+    }
+    if !tokens.get(i).map_or(false, |t| t.is_punct('!')) {
+        return None;
+    }
+    match tokens.get(i + 1).and_then(|t| t.group()) {
+        Some((Delim::None, _)) | None => None,
+        Some(_) => Some((path, i + 2)),
+    }
+}
+
+// The tokens of the group at `at` in `tokens`, the crate's.
+fn group_mut<'t>(tokens: &'t mut Vec<Token>, at: &[usize]) -> &'t mut Vec<Token> {
+    match at.split_first() {
+        None => tokens,
+        Some((&i, rest)) => match tokens[i].kind {
+            TokenKind::Group(_, ref mut inner) => group_mut(inner, rest),
+            _ => panic!("a site's path goes through groups"),
+        },
+    }
+}
+
+pub fn group<'t>(tokens: &'t [Token], at: &[usize]) -> &'t [Token] {
+    match at.split_first() {
+        None => tokens,
+        Some((&i, rest)) => group(tokens[i].group().expect("a site's path goes through groups").1, rest),
+    }
+}
+
+// Put `output` in place of the invocation `site` in `krate`, returning how
+// many tokens it took and how many are now in its place. In expression
+// position the output is one expression; in statement position the
+// invocation's semicolon is dropped when the output ends its own statement.
+pub fn splice(krate: &mut Vec<Token>, site: &Site, output: Vec<Token>) -> (usize, usize) {
+    let (i, group) = site.at.split_last().unwrap();
+    let tokens = group_mut(krate, group);
+    let mut end = i + site.len;
+    let output = if site.expr {
+        vec![expression(&output, &site.span)]
+    } else {
+        let ends_stmt = output.last().map_or(true, |last| {
+            last.is_punct(';') || last.group().map_or(false, |(d, _)| d == Delim::Brace)
+        });
+        if ends_stmt && tokens.get(end).map_or(false, |t| t.is_punct(';')) {
+            end += 1;
+        }
+        output
+    };
+    let placed = output.len();
+    tokens.splice(*i..end, output);
+    (end - i, placed)
+}
+
+// Move `site` to where it is once `removed` tokens from `at` on were taken out
+// of the crate and `placed` put in their place. Only sites after `at` in the
+// same group, or in a later group in it, move.
+pub fn shift(site: &mut Site, at: &[usize], removed: usize, placed: usize) {
+    let depth = at.len() - 1;
+    if site.at.len() > depth && site.at[..depth] == at[..depth] && site.at[depth] > at[depth] {
+        site.at[depth] = site.at[depth] - removed + placed;
+    }
+}
+
+// Whether `tokens` define a macro anywhere in them.
+pub fn defines_macro(tokens: &[Token]) -> bool {
+    tokens.windows(2).any(|pair| pair[0].is_ident("macro_rules") && pair[1].is_punct('!')) ||
+    tokens.iter().any(|token| token.group().map_or(false, |(_, inner)| defines_macro(inner)))
+}
+
+// The output of an invocation in expression position, as one token: the trailing
+// semicolon the compiler ignores is dropped, and anything but a single token
+// is kept together in a `None` group.
+fn expression(output: &[Token], site: &Span) -> Token {
+    let mut tokens = output.to_vec();
+    if tokens.len() > 1 && tokens.last().map_or(false, |t| t.is_punct(';')) {
+        tokens.pop();
+    }
+    if tokens.len() == 1 {
+        return tokens.pop().unwrap();
+    }
+    let span = match (tokens.first(), tokens.last()) {
+        (Some(first), Some(last)) => first.span.to(&last.span),
+        _ => site.clone(),
+    };
+    Token { kind: TokenKind::Group(Delim::None, tokens), span: span }
+}
@@ -0,0 +1,29 @@
+// Golden-file tests of stable-expander over the fixtures in the repository's
+// `tests/` directory, against the files under `tests/expected/stable_expander/`.
+// `cargo test --test golden -- --bless` writes the expected files from the
+// current output.
+
+#![allow(deprecated)]
+
+extern crate macro_expander_core;
+extern crate stable_expander;
+
+use macro_expander_core::golden;
+use macro_expander_core::session::{Input, SessionOptions};
+
+use stable_expander::ExpansionSession;
+
+use std::path::Path;
+
+fn main() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("../tests");
+    golden::main(&dir, "stable_expander", |fixture| {
+        let input = Input::File(fixture.to_path_buf());
+        let mut session = try!(ExpansionSession::new(input, SessionOptions::default()));
+        let steps: Vec<Vec<(String, String)>> = session.by_ref().map(|step| step.files).collect();
+        match session.error() {
+            Some(error) => Err(error.report(&format!("step {}", steps.len().saturating_sub(1)))),
+            None => Ok(steps),
+        }
+    });
+}
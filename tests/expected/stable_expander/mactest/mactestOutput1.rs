@@ -0,0 +1,8 @@
+macro_rules! foo {
+    () => {
+        let x = 1;
+    }
+}
+fn main() {
+    let x = 1;
+}
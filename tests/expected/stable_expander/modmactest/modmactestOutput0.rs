@@ -0,0 +1,16 @@
+#[macro_use]
+mod macros {
+    macro_rules! bar {
+        () => {
+            let x = 1;
+        }
+    }
+}
+macro_rules! foo {
+    () => {
+        bar!();
+    }
+}
+fn main() {
+    foo!();
+}